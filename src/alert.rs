@@ -0,0 +1,106 @@
+// Actions run when an LED error transitions to a fault (Short, Open or DNE).
+// None of them may hold up watch's polling for long: commands run in the
+// background and webhooks are given a time limit.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::process;
+use std::time::Duration;
+use pca9956b_api::models::LedError;
+use hyper::{Body, Request};
+use tokio01::prelude::FutureExt;
+use serde_json::json;
+use log::{info, warn};
+use crate::{Config, Device, error_name};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Alerts {
+    pub exec: Option<String>,
+    pub file: Option<String>,
+    pub webhook: Option<String>,
+}
+
+pub fn is_fault(error: LedError) -> bool {
+    error != LedError::NONE
+}
+
+impl Alerts {
+    pub fn raise(&self, dev: &mut Device, led: u32, prev: LedError, error: LedError, timestamp: &str) {
+        let msg = format!("LED {} error went from {} to {}", led, error_name(prev), error_name(error));
+        info!("Raising alert: {}", msg);
+        if let Some(cmd) = &self.exec {
+            alert_exec(dev.conf(), cmd, led, prev, error);
+        }
+        if let Some(file) = &self.file {
+            alert_file(file, &format!("{} {}", timestamp, msg));
+        }
+        if let Some(url) = &self.webhook {
+            let body = json!({
+                "timestamp": timestamp,
+                "bus": dev.conf().bus,
+                "addr": dev.conf().addr,
+                "led": led,
+                "previous": prev,
+                "error": error,
+                "message": msg,
+            });
+            alert_webhook(dev, url, body.to_string());
+        }
+    }
+}
+
+fn alert_exec(conf: &Config, cmd: &str, led: u32, prev: LedError, error: LedError) {
+    let result = process::Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env("PCA9956B_BUS", conf.bus.to_string())
+        .env("PCA9956B_ADDR", conf.addr.to_string())
+        .env("PCA9956B_LED", led.to_string())
+        .env("PCA9956B_PREV_ERROR", prev.to_string())
+        .env("PCA9956B_ERROR", error.to_string())
+        .spawn();
+    let mut child = match result {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Alert command {} failed to start: {}", cmd, e);
+            return;
+        },
+    };
+    // Reaped on its own thread, so that a slow command doesn't delay polling
+    let cmd = cmd.to_string();
+    std::thread::spawn(move || {
+        match child.wait() {
+            Ok(status) if status.success() => (),
+            result => warn!("Alert command {} failed: {:?}", cmd, result),
+        }
+    });
+}
+
+fn alert_file(file: &str, line: &str) {
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file)
+        .and_then(|mut f| writeln!(f, "{}", line));
+    if let Err(e) = result {
+        warn!("Failed to write alert to {}: {}", file, e);
+    }
+}
+
+fn alert_webhook(dev: &mut Device, url: &str, body: String) {
+    let req = match Request::post(url)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body)) {
+        Ok(req) => req,
+        Err(e) => {
+            warn!("Invalid alert webhook {}: {}", url, e);
+            return;
+        },
+    };
+    let result = dev.block_on(hyper::Client::new().request(req).timeout(WEBHOOK_TIMEOUT));
+    match result {
+        Ok(ref rsp) if rsp.status().is_success() => (),
+        _ => warn!("Alert webhook {} failed: {:?}", url, result),
+    }
+}
@@ -0,0 +1,159 @@
+// Write queue for bulk operations.  Writes are queued per LED and register,
+// so a later write to the same register replaces an earlier one, and then
+// flushed together: all value writes are dispatched concurrently, followed by
+// all state writes, rather than as one blocking round-trip per write.
+
+use std::collections::BTreeMap;
+use futures::future;
+use pca9956b_api::models::LedState;
+use log::{debug, info};
+use crate::device::WriteFuture;
+use crate::{Device, ValueType};
+
+#[derive(Debug, PartialEq)]
+enum Write {
+    State(i32, LedState),
+    Value(i32, ValueType, u32),
+}
+
+#[derive(Default)]
+struct Pending {
+    state: Option<LedState>,
+    pwm: Option<u32>,
+    current: Option<u32>,
+}
+
+#[derive(Default)]
+pub struct WriteQueue {
+    pending: BTreeMap<i32, Pending>,
+}
+
+impl WriteQueue {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn set_state(&mut self, led: i32, state: LedState) {
+        self.pending.entry(led).or_default().state = Some(state);
+    }
+
+    pub fn set_value(&mut self, led: i32, ty: ValueType, val: u32) {
+        let pending = self.pending.entry(led).or_default();
+        match ty {
+            ValueType::Current => pending.current = Some(val),
+            ValueType::Pwm => pending.pwm = Some(val),
+        }
+    }
+
+    // Sends all queued writes, returning a summary of the batch, which is an
+    // error if any write failed.  Writes found redundant by the write cache
+    // are skipped.
+    pub fn flush(&mut self, dev: &mut Device) -> Result<String, String> {
+        let leds = self.pending.len();
+        let (values, states) = self.take_writes();
+
+        // Values go first, as in Device::apply_set_led
+        let mut results = dispatch(dev, values);
+        results.append(&mut dispatch(dev, states));
+
+        let skipped = results.iter().filter(|x| x.is_none()).count();
+        let results: Vec<Result<String, String>> = results.into_iter().flatten().collect();
+        let writes = results.len();
+        let failed: Vec<String> = results.into_iter().filter_map(|x| x.err()).collect();
+        if failed.is_empty() {
+            let summary = if skipped > 0 {
+                format!("Applied {} writes to {} LEDs, skipped {} redundant", writes, leds, skipped)
+            } else {
+                format!("Applied {} writes to {} LEDs", writes, leds)
+            };
+            info!("{}", summary);
+            Ok(summary)
+        } else {
+            Err(format!("{} of {} writes failed: {}", failed.len(), writes, failed.join(", ")))
+        }
+    }
+
+    // Empties the queue into the value writes and the state writes to send
+    fn take_writes(&mut self) -> (Vec<Write>, Vec<Write>) {
+        let mut values = vec![];
+        let mut states = vec![];
+        for (led, write) in std::mem::take(&mut self.pending) {
+            if let Some(val) = write.current {
+                values.push(Write::Value(led, ValueType::Current, val));
+            }
+            if let Some(val) = write.pwm {
+                values.push(Write::Value(led, ValueType::Pwm, val));
+            }
+            if let Some(state) = write.state {
+                states.push(Write::State(led, state));
+            }
+        }
+        (values, states)
+    }
+}
+
+// Returns the result of each write, or None for those skipped as redundant
+fn dispatch(dev: &mut Device, writes: Vec<Write>) -> Vec<Option<Result<String, String>>> {
+    let queued = writes.len();
+    let writes: Vec<Write> = writes.into_iter()
+        .filter(|write| match *write {
+            Write::State(led, state) => dev.redundant_state(led, state).is_none(),
+            Write::Value(led, ty, val) => dev.redundant_value(led, ty, val).is_none(),
+        })
+        .collect();
+    let mut results = vec![None; queued - writes.len()];
+    if writes.is_empty() {
+        return results;
+    }
+    // The daemon serializes requests anyway, so there is nothing to gain
+    // from running them concurrently
+    if dev.conf().attach.is_some() {
+        results.extend(writes.into_iter().map(|write| Some(match write {
+            Write::State(led, state) => dev.set_led_state(led, state),
+            Write::Value(led, ty, val) => dev.set_led_value(led, ty, val),
+        })));
+        return results;
+    }
+    debug!("Dispatching {} writes", writes.len());
+    let futures: Vec<WriteFuture> = writes.iter()
+        .map(|write| match *write {
+            Write::State(led, state) => dev.led_state_request(led, state),
+            Write::Value(led, ty, val) => dev.led_value_request(led, ty, val),
+        })
+        .collect();
+    let dispatched = dev.block_on(future::join_all(futures)).unwrap_or_default();
+    if let Some(cache) = &dev.conf().write_cache {
+        for (write, result) in writes.iter().zip(&dispatched) {
+            match *write {
+                Write::State(led, state) => cache.wrote_state(led, state, result.is_ok()),
+                Write::Value(led, ty, val) => cache.wrote_value(led, ty, val, result.is_ok()),
+            }
+        }
+    }
+    results.extend(dispatched.into_iter().map(Some));
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coalesces_writes_to_the_same_register() {
+        let mut queue = WriteQueue::new();
+        queue.set_value(3, ValueType::Pwm, 10);
+        queue.set_state(3, LedState::FALSE);
+        queue.set_value(3, ValueType::Pwm, 20);
+        queue.set_value(3, ValueType::Current, 5);
+        queue.set_state(3, LedState::PWM);
+        queue.set_value(1, ValueType::Pwm, 7);
+        let (values, states) = queue.take_writes();
+        assert_eq!(values, vec![
+            Write::Value(1, ValueType::Pwm, 7),
+            Write::Value(3, ValueType::Current, 5),
+            Write::Value(3, ValueType::Pwm, 20),
+        ]);
+        assert_eq!(states, vec![Write::State(3, LedState::PWM)]);
+        assert!(queue.pending.is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+// Write suppression.  Remembers each LED's state and values, as last read from
+// or successfully written to the device, so that writes which would not
+// change anything can be skipped.  Cached values older than the staleness
+// window are not trusted, in case something else has changed the device.
+// A client attached to a daemon can't see other clients' writes, so only the
+// daemon itself skips redundant writes.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use pca9956b_api::models::{LedInfo, LedState};
+use crate::{ValueType, NUM_LEDS};
+
+#[derive(Default, Clone, Copy)]
+struct Entry {
+    state: Option<(LedState, Instant)>,
+    pwm: Option<(u32, Instant)>,
+    current: Option<(u32, Instant)>,
+}
+
+pub struct WriteCache {
+    window: Duration,
+    leds: Mutex<Vec<Entry>>,
+}
+
+impl WriteCache {
+    pub fn new(window: Duration) -> Self {
+        WriteCache {
+            window,
+            leds: Mutex::new(vec![Default::default(); NUM_LEDS]),
+        }
+    }
+
+    pub fn update(&self, info: &[LedInfo]) {
+        let now = Instant::now();
+        let mut leds = self.leds.lock().unwrap();
+        for led in info {
+            if let Some(entry) = led.index.and_then(|x| leds.get_mut(x as usize)) {
+                entry.state = led.state.map(|x| (x, now));
+                entry.pwm = led.pwm.map(|x| (x, now));
+                entry.current = led.current.map(|x| (x, now));
+            }
+        }
+    }
+
+    pub fn has_state(&self, led: i32, state: LedState) -> bool {
+        let leds = self.leds.lock().unwrap();
+        let cached = leds.get(led as usize).and_then(|x| x.state);
+        matches!(cached, Some((x, at)) if x == state && at.elapsed() <= self.window)
+    }
+
+    pub fn has_value(&self, led: i32, ty: ValueType, val: u32) -> bool {
+        let leds = self.leds.lock().unwrap();
+        let cached = leds.get(led as usize).and_then(|x| match ty {
+            ValueType::Current => x.current,
+            ValueType::Pwm => x.pwm,
+        });
+        matches!(cached, Some((x, at)) if x == val && at.elapsed() <= self.window)
+    }
+
+    // Records the outcome of a write - after a failure the LED's register is
+    // in an unknown state
+    pub fn wrote_state(&self, led: i32, state: LedState, ok: bool) {
+        let mut leds = self.leds.lock().unwrap();
+        if let Some(entry) = leds.get_mut(led as usize) {
+            entry.state = if ok { Some((state, Instant::now())) } else { None };
+        }
+    }
+
+    pub fn wrote_value(&self, led: i32, ty: ValueType, val: u32, ok: bool) {
+        let mut leds = self.leds.lock().unwrap();
+        if let Some(entry) = leds.get_mut(led as usize) {
+            let cached = if ok { Some((val, Instant::now())) } else { None };
+            match ty {
+                ValueType::Current => entry.current = cached,
+                ValueType::Pwm => entry.pwm = cached,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pca9956b_api::models::LedError;
+
+    fn info(led: u32, state: LedState, pwm: u32) -> LedInfo {
+        LedInfo {
+            index: Some(led),
+            state: Some(state),
+            pwm: Some(pwm),
+            current: Some(0),
+            error: Some(LedError::NONE),
+        }
+    }
+
+    #[test]
+    fn remembers_reads_and_writes() {
+        let cache = WriteCache::new(Duration::from_secs(60));
+        assert!(!cache.has_state(3, LedState::PWM));
+        cache.update(&[info(3, LedState::PWM, 128)]);
+        assert!(cache.has_state(3, LedState::PWM));
+        assert!(!cache.has_state(3, LedState::FALSE));
+        assert!(cache.has_value(3, ValueType::Pwm, 128));
+        assert!(cache.has_value(3, ValueType::Current, 0));
+        assert!(!cache.has_value(4, ValueType::Pwm, 128));
+        cache.wrote_value(3, ValueType::Pwm, 9, true);
+        assert!(cache.has_value(3, ValueType::Pwm, 9));
+        assert!(!cache.has_value(3, ValueType::Pwm, 128));
+    }
+
+    #[test]
+    fn forgets_values_after_the_window() {
+        let cache = WriteCache::new(Duration::from_millis(20));
+        cache.update(&[info(3, LedState::PWM, 128)]);
+        cache.wrote_state(5, LedState::TRUE, true);
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(!cache.has_state(3, LedState::PWM));
+        assert!(!cache.has_value(3, ValueType::Pwm, 128));
+        assert!(!cache.has_state(5, LedState::TRUE));
+    }
+
+    #[test]
+    fn forgets_registers_after_failed_writes() {
+        let cache = WriteCache::new(Duration::from_secs(60));
+        cache.update(&[info(3, LedState::PWM, 128)]);
+        cache.wrote_state(3, LedState::PWM, false);
+        cache.wrote_value(3, ValueType::Pwm, 128, false);
+        assert!(!cache.has_state(3, LedState::PWM));
+        assert!(!cache.has_value(3, ValueType::Pwm, 128));
+        assert!(cache.has_value(3, ValueType::Current, 0));
+    }
+}
@@ -0,0 +1,210 @@
+// The command language shared by run-script and the TUI's command palette.
+//
+// Each line holds one command.  Blank lines and anything after a # are
+// ignored.  Commands:
+//
+//   select <leds>... | none         LEDs subsequent commands apply to
+//   state [<leds>] <state>          Set the state, off|on|pwm|pwmplus
+//   current [<leds>] <0-255>        Set the current
+//   pwm [<leds>] <0-255>            Set the PWM value
+//   sleep <ms>                      Pause for the given number of milliseconds
+//   refresh                         Re-read LED info from the device
+//   assert state <state>            Check the selected LEDs against the
+//   assert current|pwm <0-255>      device
+//   assert error none|open|short|dne
+//   help                            Show the key bindings
+//   exit | quit                     Stop
+//
+// <leds> is a number, range, list or group name, or all, e.g. 0-3,8 or desk.
+// Writes apply to the LEDs given, or else to those selected.  Values may be
+// given in decimal, hex (0x..) or percent.
+
+use std::time::Duration;
+use pca9956b_api::models::{LedState, LedError};
+use crate::{LedState2, ValueType};
+use crate::{parse_leds, parse_value};
+
+#[derive(Debug, PartialEq)]
+pub enum Cmd {
+    Select(Vec<i32>),
+    State(Option<Vec<i32>>, LedState),
+    Value(Option<Vec<i32>>, ValueType, u32),
+    Sleep(Duration),
+    Refresh,
+    Assert(Check),
+    Help,
+    Exit,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Check {
+    State(LedState),
+    Value(ValueType, u32),
+    Error(LedError),
+}
+
+// Parses a line, returning None if it holds no command.  LEDs may be named by
+// any of the groups given.
+pub fn parse_line(line: &str, groups: &[(String, Vec<i32>)]) -> Result<Option<Cmd>, String> {
+    let line = match line.find('#') {
+        Some(pos) => &line[..pos],
+        None => line,
+    };
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if words.is_empty() {
+        return Ok(None);
+    }
+    let args = &words[1..];
+    let cmd = match words[0] {
+        "select" => match args {
+            [] => return Err("Expected LEDs, all or none".to_string()),
+            ["none"] => Cmd::Select(vec![]),
+            _ => Cmd::Select(parse_selection(&args.join(","), groups)?),
+        },
+        "state" => {
+            let (leds, arg) = write_args("state", "state", args, groups)?;
+            Cmd::State(leds, parse_state(arg)?)
+        },
+        "current" => {
+            let (leds, arg) = write_args("current", "value", args, groups)?;
+            Cmd::Value(leds, ValueType::Current, parse_value(arg, 255)?)
+        },
+        "pwm" => {
+            let (leds, arg) = write_args("pwm", "value", args, groups)?;
+            Cmd::Value(leds, ValueType::Pwm, parse_value(arg, 255)?)
+        },
+        "sleep" => {
+            let ms = one_arg(args)?;
+            let ms = ms.parse::<u64>().map_err(|_| format!("Invalid sleep time {}", ms))?;
+            Cmd::Sleep(Duration::from_millis(ms))
+        },
+        "assert" => Cmd::Assert(parse_check(args)?),
+        "refresh" | "help" | "exit" | "quit" if !args.is_empty() => {
+            return Err(format!("Expected no arguments, got {}", args.len()));
+        },
+        "refresh" => Cmd::Refresh,
+        "help" => Cmd::Help,
+        "exit" | "quit" => Cmd::Exit,
+        x => return Err(format!("Unknown command {}", x)),
+    };
+    Ok(Some(cmd))
+}
+
+// Resolves LEDs as numbers, ranges, group names or all, such as 0-3,desk
+pub fn parse_selection(spec: &str, groups: &[(String, Vec<i32>)]) -> Result<Vec<i32>, String> {
+    let mut leds = vec![];
+    for part in spec.split(',').map(|x| x.trim()) {
+        match groups.iter().find(|(name, _)| name == part) {
+            Some((_, group)) => leds.extend(group),
+            None if groups.is_empty() => leds.extend(parse_leds(part)?),
+            None => leds.extend(parse_leds(part).map_err(|_| format!("Invalid LEDs or group {}", part))?),
+        }
+    }
+    leds.sort_unstable();
+    leds.dedup();
+    Ok(leds)
+}
+
+// The LEDs, if given, and the argument of a write
+fn write_args<'a>(cmd: &str, arg: &str, args: &[&'a str], groups: &[(String, Vec<i32>)]) -> Result<(Option<Vec<i32>>, &'a str), String> {
+    match args {
+        [x] => Ok((None, x)),
+        [leds, x] => Ok((Some(parse_selection(leds, groups)?), x)),
+        _ => Err(format!("Expected {} [<leds>] <{}>", cmd, arg)),
+    }
+}
+
+fn one_arg<'a>(args: &[&'a str]) -> Result<&'a str, String> {
+    match args {
+        [arg] => Ok(arg),
+        _ => Err(format!("Expected 1 argument, got {}", args.len())),
+    }
+}
+
+fn parse_state(arg: &str) -> Result<LedState, String> {
+    arg.parse::<LedState2>().map(|x| x.into())
+}
+
+fn parse_check(args: &[&str]) -> Result<Check, String> {
+    match args {
+        ["state", arg] => Ok(Check::State(parse_state(arg)?)),
+        ["current", arg] => Ok(Check::Value(ValueType::Current, parse_value(arg, 255)?)),
+        ["pwm", arg] => Ok(Check::Value(ValueType::Pwm, parse_value(arg, 255)?)),
+        ["error", arg] => Ok(Check::Error(arg.to_lowercase().parse::<LedError>().map_err(|_| format!("Invalid LED error {}", arg))?)),
+        _ => Err("Expected assert state|current|pwm|error <value>".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn groups() -> Vec<(String, Vec<i32>)> {
+        vec![("desk".to_string(), vec![4, 5])]
+    }
+
+    fn parse(line: &str) -> Cmd {
+        parse_line(line, &groups()).unwrap().unwrap()
+    }
+
+    fn parse_err(line: &str) -> String {
+        parse_line(line, &[]).unwrap_err()
+    }
+
+    #[test]
+    fn skips_blank_lines_and_comments() {
+        assert_eq!(parse_line("", &[]).unwrap(), None);
+        assert_eq!(parse_line("   # just a comment", &[]).unwrap(), None);
+        assert_eq!(parse("refresh # trailing comment"), Cmd::Refresh);
+    }
+
+    #[test]
+    fn parses_selections() {
+        assert_eq!(parse("select 3"), Cmd::Select(vec![3]));
+        assert_eq!(parse("select 1 2 3"), Cmd::Select(vec![1, 2, 3]));
+        assert_eq!(parse("select 8 0-2,5"), Cmd::Select(vec![0, 1, 2, 5, 8]));
+        assert_eq!(parse("select desk 7"), Cmd::Select(vec![4, 5, 7]));
+        assert_eq!(parse("select all"), Cmd::Select((0..24).collect()));
+        assert_eq!(parse("select none"), Cmd::Select(vec![]));
+    }
+
+    #[test]
+    fn parses_writes() {
+        assert_eq!(parse("state pwmplus"), Cmd::State(None, LedState::PWMPLUS));
+        assert_eq!(parse("state desk,7 on"), Cmd::State(Some(vec![4, 5, 7]), LedState::TRUE));
+        assert_eq!(parse("current 0x20"), Cmd::Value(None, ValueType::Current, 32));
+        assert_eq!(parse("pwm 50%"), Cmd::Value(None, ValueType::Pwm, 128));
+        assert_eq!(parse("pwm 3 128"), Cmd::Value(Some(vec![3]), ValueType::Pwm, 128));
+        assert_eq!(parse("sleep 250"), Cmd::Sleep(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn parses_asserts() {
+        assert_eq!(parse("assert state off"), Cmd::Assert(Check::State(LedState::FALSE)));
+        assert_eq!(parse("assert pwm 9"), Cmd::Assert(Check::Value(ValueType::Pwm, 9)));
+        assert_eq!(parse("assert error open"), Cmd::Assert(Check::Error(LedError::OPEN)));
+    }
+
+    #[test]
+    fn parses_control_commands() {
+        assert_eq!(parse("help"), Cmd::Help);
+        assert_eq!(parse("exit"), Cmd::Exit);
+        assert_eq!(parse("quit"), Cmd::Exit);
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        assert_eq!(parse_err("select 24"), "Invalid LEDs 24");
+        assert_eq!(parse_line("select lounge", &groups()).unwrap_err(), "Invalid LEDs or group lounge");
+        assert_eq!(parse_err("select"), "Expected LEDs, all or none");
+        assert_eq!(parse_err("pwm 256"), "Value 256 exceeds maximum 255");
+        assert_eq!(parse_err("pwm lots"), "Invalid value lots");
+        assert_eq!(parse_err("state dim"), "Invalid LED state dim");
+        assert_eq!(parse_err("state"), "Expected state [<leds>] <state>");
+        assert_eq!(parse_err("current 1 2 3"), "Expected current [<leds>] <value>");
+        assert_eq!(parse_err("sleep 1 2"), "Expected 1 argument, got 2");
+        assert_eq!(parse_err("refresh now"), "Expected no arguments, got 1");
+        assert_eq!(parse_err("assert state"), "Expected assert state|current|pwm|error <value>");
+        assert_eq!(parse_err("blink 3"), "Unknown command blink");
+    }
+}
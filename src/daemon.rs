@@ -0,0 +1,167 @@
+// Control daemon.  Owns the connection to the device and serves it to other
+// instances of this program over a Unix socket, so several TUIs and
+// subcommands can share one device without their writes interleaving.
+//
+// Clients attach with --attach <socket>.  Each request is one line of JSON,
+// answered with one line of JSON:
+//
+//   {"cmd":"info"}                                 {"ok":true,"leds":[...]}
+//   {"cmd":"state","led":3,"state":"pwm"}          {"ok":true,"msg":"..."}
+//   {"cmd":"value","led":3,"type":"pwm","value":9} {"ok":false,"msg":"..."}
+//
+// Requests are handled one at a time, in the order received.
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{channel, Sender};
+use pca9956b_api::models::{LedInfo, LedState};
+use serde::{Deserialize, Serialize};
+use log::{debug, info, warn};
+use crate::{Device, ValueType};
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "lowercase")]
+enum Request {
+    Info,
+    State { led: i32, state: LedState },
+    Value { led: i32, #[serde(rename = "type")] ty: String, value: u32 },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Response {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    msg: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    leds: Option<Vec<LedInfo>>,
+}
+
+pub fn run_daemon(dev: &mut Device, socket: &str) -> Result<(), String> {
+    if dev.conf().attach.is_some() {
+        return Err("The daemon cannot itself attach to a daemon".to_string());
+    }
+    // Remove any socket left behind by a previous daemon, but nothing else
+    if let Ok(meta) = std::fs::symlink_metadata(socket) {
+        if !meta.file_type().is_socket() {
+            return Err(format!("{} exists and is not a socket", socket));
+        }
+        if UnixStream::connect(socket).is_err() {
+            std::fs::remove_file(socket).map_err(|e| format!("Failed to remove stale socket {}: {}", socket, e))?;
+        }
+    }
+    let listener = UnixListener::bind(socket).map_err(|e| format!("Failed to listen on {}: {}", socket, e))?;
+
+    let (tx, rx) = channel::<(Request, Sender<Response>)>();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let tx = tx.clone();
+                    std::thread::spawn(move || handle_client(stream, tx));
+                },
+                Err(e) => warn!("Failed to accept connection: {}", e),
+            }
+        }
+    });
+
+    for (req, reply) in rx {
+        let rsp = match req {
+            Request::Info => match dev.get_info() {
+                Ok(leds) => Response { ok: true, msg: None, leds: Some(leds) },
+                Err(err) => Response { ok: false, msg: Some(err), leds: None },
+            },
+            Request::State { led, state } => result(dev.set_led_state(led, state)),
+            Request::Value { led, ty, value } => match parse_value_type(&ty) {
+                Ok(ty) => result(dev.set_led_value(led, ty, value)),
+                Err(err) => result(Err(err)),
+            },
+        };
+        reply.send(rsp).ok();
+    }
+    Ok(())
+}
+
+fn result(result: Result<String, String>) -> Response {
+    match result {
+        Ok(msg) => {
+            info!("{}", msg);
+            Response { ok: true, msg: Some(msg), leds: None }
+        },
+        Err(msg) => Response { ok: false, msg: Some(msg), leds: None },
+    }
+}
+
+fn parse_value_type(ty: &str) -> Result<ValueType, String> {
+    match ty {
+        "current" => Ok(ValueType::Current),
+        "pwm" => Ok(ValueType::Pwm),
+        _ => Err(format!("Invalid value type {}", ty)),
+    }
+}
+
+fn handle_client(stream: UnixStream, tx: Sender<(Request, Sender<Response>)>) {
+    let mut writer = match stream.try_clone() {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Failed to set up client connection: {}", e);
+            return;
+        },
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        debug!("Received request {}", line);
+        let rsp = match serde_json::from_str::<Request>(&line) {
+            Ok(req) => {
+                let (reply, rx) = channel();
+                if tx.send((req, reply)).is_err() {
+                    return;
+                }
+                match rx.recv() {
+                    Ok(x) => x,
+                    Err(_) => return,
+                }
+            },
+            Err(e) => Response { ok: false, msg: Some(e.to_string()), leds: None },
+        };
+        let rsp = serde_json::to_string(&rsp).unwrap();
+        if writeln!(writer, "{}", rsp).is_err() {
+            return;
+        }
+    }
+}
+
+fn request(socket: &str, req: &Request) -> Result<Response, String> {
+    let mut stream = UnixStream::connect(socket).map_err(|e| format!("Failed to connect to daemon at {}: {}", socket, e))?;
+    writeln!(stream, "{}", serde_json::to_string(req).unwrap()).map_err(|e| e.to_string())?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).map_err(|e| e.to_string())?;
+    serde_json::from_str(&line).map_err(|e| format!("Invalid response from daemon: {}", e))
+}
+
+pub fn get_info_attached(socket: &str) -> Result<Vec<LedInfo>, String> {
+    let rsp = request(socket, &Request::Info)?;
+    match (rsp.ok, rsp.leds) {
+        (true, Some(leds)) => Ok(leds),
+        _ => Err(rsp.msg.unwrap_or_else(|| "Failure to get PCA9956B info".to_string())),
+    }
+}
+
+pub fn set_led_state_attached(socket: &str, led: i32, state: LedState) -> Result<String, String> {
+    let rsp = request(socket, &Request::State { led, state })?;
+    let msg = rsp.msg.unwrap_or_default();
+    if rsp.ok { Ok(msg) } else { Err(msg) }
+}
+
+pub fn set_led_value_attached(socket: &str, led: i32, ty: ValueType, value: u32) -> Result<String, String> {
+    let ty = match ty {
+        ValueType::Current => "current",
+        ValueType::Pwm => "pwm",
+    }.to_string();
+    let rsp = request(socket, &Request::Value { led, ty, value })?;
+    let msg = rsp.msg.unwrap_or_default();
+    if rsp.ok { Ok(msg) } else { Err(msg) }
+}
@@ -0,0 +1,212 @@
+// A handle on one PCA9956B, reached either through the HTTP API or through a
+// control daemon.  All reads and writes of the device go through here, so
+// that write suppression and daemon attachment apply to every front-end.
+
+use pca9956b_api::{ApiNoContext, ContextWrapperExt, GetLedInfoAllResponse};
+use pca9956b_api::{SetLedStateResponse, SetLedCurrentResponse, SetLedPwmResponse};
+use pca9956b_api::models::{LedInfo, LedState};
+use swagger::{make_context, make_context_ty};
+use swagger::{ContextBuilder, EmptyContext, XSpanIdString, Push, AuthData};
+use futures::Future;
+use log::info;
+use crate::cache::WriteCache;
+use crate::daemon;
+use crate::led::{LedState2, SetLed, ValueType};
+use crate::rt::Core;
+
+pub struct Config {
+    pub https: bool,
+    pub host: String,
+    pub port: String,
+    pub bus: i32,
+    pub addr: i32,
+    pub attach: Option<String>,
+    pub write_cache: Option<WriteCache>,
+}
+
+pub type ApiClient = pca9956b_api::client::Client<hyper::client::ResponseFuture>;
+pub type ClientContext = make_context_ty!(ContextBuilder, EmptyContext, Option<AuthData>, XSpanIdString);
+pub type Client<'a> = swagger::context::ContextWrapper<'a, ApiClient, ClientContext>;
+
+// A write request, which resolves to a description of the write or why it
+// failed
+pub type WriteFuture = Box<dyn Future<Item = Result<String, String>, Error = ()> + Send>;
+
+pub struct Device {
+    conf: Config,
+    core: Core,
+    api: ApiClient,
+    context: ClientContext,
+}
+
+impl Device {
+    pub fn new(conf: Config) -> Result<Self, String> {
+        let core = Core::new().map_err(|e| format!("Failed to create runtime: {}", e))?;
+        let api = create_client(&conf)?;
+        let context = make_context!(ContextBuilder, EmptyContext, None as Option<AuthData>, XSpanIdString(uuid::Uuid::new_v4().to_string()));
+        Ok(Device { conf, core, api, context })
+    }
+
+    pub fn conf(&self) -> &Config {
+        &self.conf
+    }
+
+    pub fn client(&self) -> Client<'_> {
+        self.api.with_context(self.context.clone())
+    }
+
+    // Runs a request, or any other future needing the API client's runtime,
+    // to completion
+    pub fn block_on<F>(&mut self, future: F) -> Result<F::Item, F::Error>
+    where
+        F: Future + Send + 'static,
+        F::Item: Send,
+        F::Error: Send,
+    {
+        self.core.run(future)
+    }
+
+    pub fn get_info(&mut self) -> Result<Vec<LedInfo>, String> {
+        let info = if let Some(socket) = &self.conf.attach {
+            daemon::get_info_attached(socket)?
+        } else {
+            let request = self.client().get_led_info_all(self.conf.bus, self.conf.addr);
+            let result = self.core.run(request);
+            match result {
+                Ok(GetLedInfoAllResponse::OK(info)) => info.into(),
+                _ => return Err(format!("Failure to get PCA9956B info: {:?}", result)),
+            }
+        };
+        if let Some(cache) = &self.conf.write_cache {
+            cache.update(&info);
+        }
+        Ok(info)
+    }
+
+    pub fn set_led_state(&mut self, led: i32, state: LedState) -> Result<String, String> {
+        if let Some(msg) = self.redundant_state(led, state) {
+            return Ok(msg);
+        }
+        let result = self.write_led_state(led, state);
+        if let Some(cache) = &self.conf.write_cache {
+            cache.wrote_state(led, state, result.is_ok());
+        }
+        result
+    }
+
+    fn write_led_state(&mut self, led: i32, state: LedState) -> Result<String, String> {
+        if let Some(socket) = &self.conf.attach {
+            return daemon::set_led_state_attached(socket, led, state);
+        }
+        let request = self.led_state_request(led, state);
+        self.core.run(request).unwrap_or_else(|_| Err(format!("Failed to set LED {} to {}", led, LedState2::from(state))))
+    }
+
+    pub fn set_led_value(&mut self, led: i32, ty: ValueType, val: u32) -> Result<String, String> {
+        if let Some(msg) = self.redundant_value(led, ty, val) {
+            return Ok(msg);
+        }
+        let result = self.write_led_value(led, ty, val);
+        if let Some(cache) = &self.conf.write_cache {
+            cache.wrote_value(led, ty, val, result.is_ok());
+        }
+        result
+    }
+
+    fn write_led_value(&mut self, led: i32, ty: ValueType, val: u32) -> Result<String, String> {
+        if let Some(socket) = &self.conf.attach {
+            return daemon::set_led_value_attached(socket, led, ty, val);
+        }
+        let request = self.led_value_request(led, ty, val);
+        self.core.run(request).unwrap_or_else(|_| Err(format!("Failed to set LED {} {} to {}", led, ty, val)))
+    }
+
+    // If the write cache shows the LED is already in this state, logs and
+    // returns why the write can be skipped
+    pub fn redundant_state(&self, led: i32, state: LedState) -> Option<String> {
+        let cache = self.conf.write_cache.as_ref()?;
+        if !cache.has_state(led, state) {
+            return None;
+        }
+        let msg = format!("LED {} already {}", led, LedState2::from(state));
+        info!("Skipped redundant write: {}", msg);
+        Some(msg)
+    }
+
+    pub fn redundant_value(&self, led: i32, ty: ValueType, val: u32) -> Option<String> {
+        let cache = self.conf.write_cache.as_ref()?;
+        if !cache.has_value(led, ty, val) {
+            return None;
+        }
+        let msg = format!("LED {} {} already {}", led, ty, val);
+        info!("Skipped redundant write: {}", msg);
+        Some(msg)
+    }
+
+    // The API requests behind the writes above, bypassing the write cache and
+    // daemon, for callers dispatching several at once
+    pub fn led_state_request(&self, led: i32, state: LedState) -> WriteFuture {
+        let s2: LedState2 = state.into();
+        let request = self.client().set_led_state(self.conf.bus, self.conf.addr, led, state);
+        Box::new(request.then(move |result| {
+            Ok(match result {
+                Ok(SetLedStateResponse::OK) => Ok(format!("Set LED {} to {}", led, s2)),
+                _ => {
+                    info!("Failed to set LED {} to {}: {:?}\n", led, s2, result);
+                    Err(format!("Failed to set LED {} to {}", led, s2))
+                },
+            })
+        }))
+    }
+
+    pub fn led_value_request(&self, led: i32, ty: ValueType, val: u32) -> WriteFuture {
+        let client = self.client();
+        let request: Box<dyn Future<Item = bool, Error = String> + Send> = match ty {
+            ValueType::Current => Box::new(client.set_led_current(self.conf.bus, self.conf.addr, led, val as i32)
+                .map(|x| x == SetLedCurrentResponse::OK)
+                .map_err(|e| format!("{:?}", e))),
+            ValueType::Pwm => Box::new(client.set_led_pwm(self.conf.bus, self.conf.addr, led, val as i32)
+                .map(|x| x == SetLedPwmResponse::OK)
+                .map_err(|e| format!("{:?}", e))),
+        };
+        Box::new(request.then(move |result| {
+            Ok(match result {
+                Ok(true) => Ok(format!("Set LED {} {} to {}", led, ty, val)),
+                _ => {
+                    info!("Failed to set LED {} {} to {}: {:?}\n", led, ty, val, result);
+                    Err(format!("Failed to set LED {} {} to {}", led, ty, val))
+                },
+            })
+        }))
+    }
+
+    pub fn apply_set_led(&mut self, led: i32, set: &SetLed) -> Result<Vec<String>, String> {
+        let state = set.validate()?;
+        let mut results = vec![];
+        // Values go first so that an LED being turned on comes up at the new level
+        if let Some(current) = set.current {
+            results.push(self.set_led_value(led, ValueType::Current, current)?);
+        }
+        if let Some(pwm) = set.pwm {
+            results.push(self.set_led_value(led, ValueType::Pwm, pwm)?);
+        }
+        if let Some(state) = state {
+            results.push(self.set_led_state(led, state)?);
+        }
+        Ok(results)
+    }
+}
+
+fn create_client(conf: &Config) -> Result<ApiClient, String> {
+    let base_url = format!("{}://{}:{}",
+                           if conf.https { "https" } else { "http" },
+                           conf.host,
+                           conf.port);
+    if conf.https {
+        pca9956b_api::Client::try_new_https(&base_url)
+            .map_err(|e| format!("Failed to create HTTPS client: {:?}", e))
+    } else {
+        pca9956b_api::Client::try_new_http(&base_url)
+            .map_err(|e| format!("Failed to create HTTP client: {:?}", e))
+    }
+}
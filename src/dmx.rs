@@ -0,0 +1,251 @@
+// DMX input over Art-Net or sACN (E1.31).  Consecutive DMX channels, starting
+// at a configurable channel, are mapped onto a set of LEDs' PWM values.
+//
+// Updates are rate limited: changes are accumulated and at most one batch of
+// writes is made per update period, so a desk sending 44 frames a second does
+// not flood the HTTP API.
+
+use std::net::{Ipv4Addr, UdpSocket};
+use std::time::{Duration, Instant};
+use pca9956b_api::models::LedState;
+use log::{debug, info, warn};
+use crate::{Device, ValueType};
+
+const ARTNET_PORT: u16 = 6454;
+const SACN_PORT: u16 = 5568;
+const ARTNET_ID: &[u8] = b"Art-Net\0";
+const ARTNET_OP_DMX: u16 = 0x5000;
+const SACN_ID: &[u8] = b"ASC-E1.17\0\0\0";
+const SACN_DATA_OFFSET: usize = 126;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    ArtNet,
+    Sacn,
+}
+
+impl std::str::FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "artnet" => Ok(Protocol::ArtNet),
+            "sacn" => Ok(Protocol::Sacn),
+            _ => Err(format!("Invalid DMX protocol {}", s)),
+        }
+    }
+}
+
+impl Protocol {
+    pub fn port(self) -> u16 {
+        match self {
+            Protocol::ArtNet => ARTNET_PORT,
+            Protocol::Sacn => SACN_PORT,
+        }
+    }
+}
+
+pub struct DmxConfig {
+    pub protocol: Protocol,
+    pub bind: String,
+    pub universe: u16,
+    pub start_channel: usize,
+    pub leds: Vec<i32>,
+    pub rate: u32,
+}
+
+impl DmxConfig {
+    // The first and last DMX channels mapped onto LEDs
+    pub fn channels(&self) -> Result<(usize, usize), String> {
+        match self.start_channel.checked_add(self.leds.len()) {
+            Some(end) if self.start_channel >= 1 && !self.leds.is_empty() && end - 1 <= 512 => Ok((self.start_channel, end - 1)),
+            _ => Err("DMX channels must be within 1-512".to_string()),
+        }
+    }
+}
+
+pub fn run_dmx(dev: &mut Device, dmx: &DmxConfig) -> Result<(), String> {
+    dmx.channels()?;
+    let port = dmx.protocol.port();
+    let socket = UdpSocket::bind((dmx.bind.as_str(), port))
+        .map_err(|e| format!("Failed to listen on {}:{}: {}", dmx.bind, port, e))?;
+    if dmx.protocol == Protocol::Sacn {
+        let group = Ipv4Addr::new(239, 255, (dmx.universe >> 8) as u8, dmx.universe as u8);
+        if let Err(e) = socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED) {
+            warn!("Failed to join sACN multicast group {}: {}", group, e);
+        }
+    }
+    // PWM values only affect LEDs in the PWM state
+    for led in &dmx.leds {
+        match dev.set_led_state(*led, LedState::PWM) {
+            Ok(x) => info!("{}", x),
+            Err(x) => warn!("{}", x),
+        }
+    }
+
+    let period = Duration::from_millis(1000 / dmx.rate.max(1) as u64);
+    let mut sent: Vec<Option<u32>> = vec![None; dmx.leds.len()];
+    let mut pending: Vec<Option<u32>> = vec![None; dmx.leds.len()];
+    let mut next_send = Instant::now();
+    let mut buf = [0u8; 1024];
+    loop {
+        let waiting = pending.iter().any(|x| x.is_some());
+        let timeout = if waiting {
+            Some(next_send.saturating_duration_since(Instant::now()).max(Duration::from_millis(1)))
+        } else {
+            None
+        };
+        socket.set_read_timeout(timeout).unwrap();
+        match socket.recv_from(&mut buf) {
+            Ok((len, src)) => match parse(dmx.protocol, &buf[..len]) {
+                Some((universe, data)) if universe == dmx.universe => {
+                    debug!("Received {} channels from {}", data.len(), src);
+                    for (ii, val) in channel_values(dmx, data).into_iter().enumerate() {
+                        if let Some(val) = val {
+                            pending[ii] = if sent[ii] == Some(val) { None } else { Some(val) };
+                        }
+                    }
+                },
+                Some((universe, _)) => debug!("Ignoring universe {} from {}", universe, src),
+                None => debug!("Ignoring invalid packet from {}", src),
+            },
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => (),
+            Err(e) => warn!("Failed to receive DMX: {}", e),
+        }
+
+        if Instant::now() >= next_send && pending.iter().any(|x| x.is_some()) {
+            for (ii, val) in pending.iter_mut().enumerate() {
+                if let Some(val) = val.take() {
+                    match dev.set_led_value(dmx.leds[ii], ValueType::Pwm, val) {
+                        Ok(x) => {
+                            debug!("{}", x);
+                            sent[ii] = Some(val);
+                        },
+                        Err(x) => warn!("{}", x),
+                    }
+                }
+            }
+            next_send = Instant::now() + period;
+        }
+    }
+}
+
+fn channel_values(dmx: &DmxConfig, data: &[u8]) -> Vec<Option<u32>> {
+    (0..dmx.leds.len())
+        .map(|ii| data.get(dmx.start_channel - 1 + ii).map(|x| *x as u32))
+        .collect()
+}
+
+// Returns the universe and DMX channel data of a DMX data packet
+fn parse(protocol: Protocol, packet: &[u8]) -> Option<(u16, &[u8])> {
+    match protocol {
+        Protocol::ArtNet => parse_artnet(packet),
+        Protocol::Sacn => parse_sacn(packet),
+    }
+}
+
+fn parse_artnet(packet: &[u8]) -> Option<(u16, &[u8])> {
+    if packet.len() < 18 || &packet[0..8] != ARTNET_ID {
+        return None;
+    }
+    if u16::from_le_bytes([packet[8], packet[9]]) != ARTNET_OP_DMX {
+        return None;
+    }
+    let universe = u16::from_le_bytes([packet[14], packet[15]]) & 0x7fff;
+    let len = u16::from_be_bytes([packet[16], packet[17]]) as usize;
+    packet.get(18..18 + len).map(|data| (universe, data))
+}
+
+fn parse_sacn(packet: &[u8]) -> Option<(u16, &[u8])> {
+    if packet.len() < SACN_DATA_OFFSET || &packet[4..16] != SACN_ID {
+        return None;
+    }
+    // Root vector must be E1.31 data, framing vector DMP data
+    if packet[18..22] != [0, 0, 0, 4] || packet[40..44] != [0, 0, 0, 2] {
+        return None;
+    }
+    // Ignore preview data and non-zero start codes (e.g. per-channel priority)
+    if packet[112] & 0x80 != 0 || packet[125] != 0 {
+        return None;
+    }
+    let universe = u16::from_be_bytes([packet[113], packet[114]]);
+    // Property value count includes the start code
+    let count = u16::from_be_bytes([packet[123], packet[124]]) as usize;
+    packet.get(SACN_DATA_OFFSET..SACN_DATA_OFFSET + count.saturating_sub(1)).map(|data| (universe, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn artnet(opcode: u16, universe: u16, len: u16, data: &[u8]) -> Vec<u8> {
+        let mut packet = ARTNET_ID.to_vec();
+        packet.extend_from_slice(&opcode.to_le_bytes());
+        // Protocol version, sequence and physical port
+        packet.extend_from_slice(&[0, 14, 0, 0]);
+        packet.extend_from_slice(&universe.to_le_bytes());
+        packet.extend_from_slice(&len.to_be_bytes());
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    fn sacn(universe: u16, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0u8; SACN_DATA_OFFSET];
+        packet[4..16].copy_from_slice(SACN_ID);
+        packet[18..22].copy_from_slice(&[0, 0, 0, 4]);
+        packet[40..44].copy_from_slice(&[0, 0, 0, 2]);
+        packet[113..115].copy_from_slice(&universe.to_be_bytes());
+        packet[123..125].copy_from_slice(&(data.len() as u16 + 1).to_be_bytes());
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    #[test]
+    fn parses_artnet() {
+        let packet = artnet(ARTNET_OP_DMX, 0x8103, 3, &[1, 2, 3]);
+        assert_eq!(parse_artnet(&packet), Some((0x0103, &[1u8, 2, 3][..])));
+        assert_eq!(parse_artnet(&artnet(0x2000, 1, 3, &[1, 2, 3])), None);
+        assert_eq!(parse_artnet(&artnet(ARTNET_OP_DMX, 1, 4, &[1, 2, 3])), None);
+        assert_eq!(parse_artnet(&packet[..17]), None);
+        let mut packet = packet;
+        packet[0] = b'a';
+        assert_eq!(parse_artnet(&packet), None);
+    }
+
+    #[test]
+    fn parses_sacn() {
+        let packet = sacn(7, &[9, 8]);
+        assert_eq!(parse_sacn(&packet), Some((7, &[9u8, 8][..])));
+        let mut bad = packet.clone();
+        bad[21] = 8;
+        assert_eq!(parse_sacn(&bad), None);
+        let mut bad = packet.clone();
+        bad[43] = 1;
+        assert_eq!(parse_sacn(&bad), None);
+        let mut preview = packet.clone();
+        preview[112] = 0x80;
+        assert_eq!(parse_sacn(&preview), None);
+        let mut priority = packet.clone();
+        priority[125] = 0xdd;
+        assert_eq!(parse_sacn(&priority), None);
+        assert_eq!(parse_sacn(&packet[..SACN_DATA_OFFSET + 1]), None);
+        assert_eq!(parse_sacn(&packet[..SACN_DATA_OFFSET - 1]), None);
+    }
+
+    #[test]
+    fn checks_channel_range() {
+        let dmx = |start_channel, leds: Vec<i32>| DmxConfig {
+            protocol: Protocol::ArtNet,
+            bind: "0.0.0.0".to_string(),
+            universe: 1,
+            start_channel,
+            leds,
+            rate: 20,
+        };
+        assert_eq!(dmx(1, (0..24).collect()).channels(), Ok((1, 24)));
+        assert_eq!(dmx(489, (0..24).collect()).channels(), Ok((489, 512)));
+        assert!(dmx(0, (0..24).collect()).channels().is_err());
+        assert!(dmx(490, (0..24).collect()).channels().is_err());
+        assert!(dmx(usize::MAX, vec![0]).channels().is_err());
+    }
+}
@@ -0,0 +1,123 @@
+// Home Assistant MQTT discovery.  Each LED, or each configured group of LEDs,
+// is advertised as a dimmable light using the JSON schema.  Brightness maps
+// to PWM, and on/off to the PWM/Off LED states.
+//
+// Lights are controlled via <base>/light/<id>/set and report their state to
+// <base>/light/<id>, where <id> is the LED number or group name.
+
+use pca9956b_api::models::{LedInfo, LedState};
+use serde::Deserialize;
+use serde_json::json;
+use log::info;
+use crate::{Config, Device, ValueType, NUM_LEDS};
+
+pub struct HassConfig {
+    pub discovery_prefix: String,
+    pub groups: Vec<(String, Vec<i32>)>,
+}
+
+pub struct Light {
+    pub id: String,
+    name: String,
+    leds: Vec<i32>,
+}
+
+#[derive(Deserialize)]
+struct LightCommand {
+    state: Option<String>,
+    brightness: Option<u32>,
+}
+
+pub fn lights(hass: &HassConfig) -> Vec<Light> {
+    if hass.groups.is_empty() {
+        (0..NUM_LEDS as i32)
+            .map(|led| Light {
+                id: led.to_string(),
+                name: format!("LED {}", led),
+                leds: vec![led],
+            })
+            .collect()
+    } else {
+        hass.groups.iter()
+            .map(|(name, leds)| Light {
+                id: name.clone(),
+                name: name.clone(),
+                leds: leds.clone(),
+            })
+            .collect()
+    }
+}
+
+pub fn discovery(hass: &HassConfig, conf: &Config, base: &str, light: &Light) -> (String, String) {
+    let device_id = format!("pca9956b_{}_{}", conf.bus, conf.addr);
+    let topic = format!("{}/light/{}/{}/config", hass.discovery_prefix, device_id, light.id);
+    let payload = json!({
+        "name": light.name,
+        "unique_id": format!("{}_{}", device_id, light.id),
+        "schema": "json",
+        "brightness": true,
+        "brightness_scale": 255,
+        "command_topic": format!("{}/light/{}/set", base, light.id),
+        "state_topic": format!("{}/light/{}", base, light.id),
+        "availability_topic": format!("{}/status", base),
+        "device": {
+            "identifiers": [device_id],
+            "name": format!("PCA9956B bus {} address {}", conf.bus, conf.addr),
+            "model": "PCA9956B",
+        },
+    });
+    (topic, payload.to_string())
+}
+
+pub fn state(light: &Light, info: &[LedInfo]) -> String {
+    let on: Vec<&LedInfo> = light.leds.iter()
+        .filter_map(|led| info.get(*led as usize))
+        .filter(|x| x.state.is_some() && x.state != Some(LedState::FALSE))
+        .collect();
+    // A fully on LED has no meaningful PWM value, so report it as brightest
+    let brightness = on.iter()
+        .map(|x| if x.state == Some(LedState::TRUE) { 255 } else { x.pwm.unwrap_or(0) })
+        .max();
+    match brightness {
+        Some(brightness) => json!({"state": "ON", "brightness": brightness}).to_string(),
+        None => json!({"state": "OFF"}).to_string(),
+    }
+}
+
+pub fn apply(dev: &mut Device, light: &Light, payload: &[u8]) -> Result<(), String> {
+    let cmd: LightCommand = serde_json::from_slice(payload).map_err(|e| e.to_string())?;
+    let on = match cmd.state.as_deref() {
+        Some("ON") => true,
+        Some("OFF") => false,
+        Some(x) => return Err(format!("Invalid light state {}", x)),
+        None if cmd.brightness.is_some() => true,
+        None => return Err("Expected state or brightness".to_string()),
+    };
+    // Read the current PWM values rather than relying on what was last
+    // published, which is forgotten whenever the broker connection drops
+    let info = match (on, cmd.brightness) {
+        (true, None) => dev.get_info()?,
+        _ => vec![],
+    };
+    for led in &light.leds {
+        if on {
+            // Turning on an LED whose PWM is 0 would leave it dark
+            let pwm = info.get(*led as usize).and_then(|x| x.pwm).unwrap_or(0);
+            let brightness = match cmd.brightness {
+                Some(x) => Some(x.min(255)),
+                None if pwm == 0 => Some(255),
+                None => None,
+            };
+            if let Some(brightness) = brightness {
+                let result = dev.set_led_value(*led, ValueType::Pwm, brightness)?;
+                info!("{}", result);
+            }
+            let result = dev.set_led_state(*led, LedState::PWM)?;
+            info!("{}", result);
+        } else {
+            let result = dev.set_led_state(*led, LedState::FALSE)?;
+            info!("{}", result);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,741 @@
+// The TUI's command processor.  A pure reducer: given the current State and a
+// decoded key press, returns the new State and the Effects the front-end
+// should carry out - requests to make of the device, and what to redraw.
+
+use pca9956b_api::models::{LedInfo, LedState};
+use crate::command::{parse_line, parse_selection, Cmd};
+use crate::keymap::{Command, Keymap, ROW_LEDS};
+use crate::led::{LedState2, ValueType, NUM_LEDS, format_leds, get_value, parse_value};
+
+pub use crate::keymap::Key;
+
+pub const MAX_VALUE: u32 = 255;
+
+// Steps taken by the fine, medium and coarse adjustment keys, in units, or
+// in percent when percentage steps are selected
+pub const STEPS: [i32; 3] = [1, 10, 16];
+pub const PERCENT_STEPS: [i32; 3] = [1, 5, 10];
+
+// Longest value which can be typed in, e.g. 100.0%, LED selection and
+// command
+const MAX_VALUE_ENTRY: usize = 6;
+const MAX_LEDS_ENTRY: usize = 32;
+const MAX_COMMAND_ENTRY: usize = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntryKind {
+    Value,
+    Leds,
+    Command,
+}
+
+// Text being typed in, while in an entry mode
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub kind: EntryKind,
+    pub text: String,
+}
+
+#[derive(Clone)]
+pub struct State {
+    // Selected LEDs, in order
+    pub selected: Vec<i32>,
+    pub value_type: Option<ValueType>,
+    pub new_value: Option<u32>,
+    pub percent_steps: bool,
+    pub entry: Option<Entry>,
+    // Whether the key bindings are shown over the controls
+    pub help: bool,
+    pub max_current: u32,
+    // Named groups of LEDs which can be typed in when selecting LEDs
+    pub groups: Vec<(String, Vec<i32>)>,
+    pub keymap: Keymap,
+    pub info: Vec<LedInfo>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            selected: vec![],
+            value_type: None,
+            new_value: None,
+            percent_steps: false,
+            entry: None,
+            help: false,
+            max_current: MAX_VALUE,
+            groups: vec![],
+            keymap: Keymap::default(),
+            info: vec![],
+        }
+    }
+}
+
+impl State {
+    pub fn max_value(&self, ty: ValueType) -> u32 {
+        match ty {
+            ValueType::Current => self.max_current,
+            ValueType::Pwm => MAX_VALUE,
+        }
+    }
+
+    // Resolves LEDs typed in, as numbers, ranges, group names or all, such
+    // as 0-3,desk
+    pub fn parse_selection(&self, spec: &str) -> Result<Vec<i32>, String> {
+        parse_selection(spec, &self.groups)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    Refresh,
+    SetState(Vec<i32>, LedState),
+    SetValue(Vec<i32>, ValueType, u32),
+}
+
+// A change to the new value being edited, either by a fine (0), medium (1) or
+// coarse (2) step up or down, or to an absolute value
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Adjust {
+    Up(usize),
+    Down(usize),
+    To(u32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Effect {
+    Request(Request),
+    ShowSelected,
+    // Redraw the whole screen, e.g. to show or hide help
+    Redraw,
+    // Scroll the activity log back by this many pages, or forward if negative
+    ScrollLog(i32),
+    Info(String),
+    Exit(String),
+}
+
+fn describe_leds(leds: &[i32]) -> String {
+    match leds {
+        [led] => format!("LED {}", led),
+        _ => format!("{} LEDs", leds.len()),
+    }
+}
+
+pub fn process_input(state: State, key: Key) -> (State, Vec<Effect>) {
+    let mut state = state;
+    let mut effects = vec![];
+    // Any key dismisses help
+    if state.help {
+        state.help = false;
+        effects.push(Effect::Redraw);
+        return (state, effects);
+    }
+    if state.entry.is_some() {
+        process_entry(&mut state, &mut effects, key);
+        return (state, effects);
+    }
+    let has_selection = !state.selected.is_empty();
+    let cmd = match state.keymap.command(key) {
+        Some(x) => x,
+        None => {
+            effects.push(Effect::Info(format!("Unknown key-press {}", key)));
+            return (state, effects);
+        },
+    };
+    match cmd {
+        Command::Refresh => refresh(&mut effects),
+        Command::Mode(ledstate) => {
+            if has_selection {
+                let leds = state.selected.clone();
+                set_state(&mut effects, leds, ledstate);
+            }
+        },
+        Command::SelectNone => select(&mut state, &mut effects, vec![]),
+        Command::SelectAll => select(&mut state, &mut effects, (0..NUM_LEDS as i32).collect()),
+        Command::SelectLed(led) => select(&mut state, &mut effects, vec![led]),
+        Command::ToggleLed(led) => {
+            let mut leds = state.selected.clone();
+            match leds.iter().position(|x| *x == led) {
+                Some(ii) => {
+                    leds.remove(ii);
+                },
+                None => leds.push(led),
+            }
+            select(&mut state, &mut effects, leds);
+        },
+        Command::SelectRow(row) => select(&mut state, &mut effects, (row * ROW_LEDS..(row + 1) * ROW_LEDS).collect()),
+        Command::TypeLeds => {
+            state.entry = Some(Entry { kind: EntryKind::Leds, text: String::new() });
+            effects.push(Effect::ShowSelected);
+            effects.push(Effect::Info("Enter LEDs (e.g. 4-11,20 or a group), <Enter> to select, <Esc> to cancel".to_string()));
+        },
+        Command::Value(value_type) => {
+            let info = if has_selection {
+                state.value_type = Some(value_type);
+                format!("Selected {} Value", value_type)
+            } else {
+                "No LED selected".to_string()
+            };
+            state.new_value = None;
+            effects.push(Effect::ShowSelected);
+            effects.push(Effect::Info(info));
+        },
+        Command::Up(size) => adjust(&mut state, &mut effects, Adjust::Up(size)),
+        Command::Down(size) => adjust(&mut state, &mut effects, Adjust::Down(size)),
+        Command::Min => adjust(&mut state, &mut effects, Adjust::To(0)),
+        Command::Max => adjust(&mut state, &mut effects, Adjust::To(MAX_VALUE)),
+        Command::PercentSteps => {
+            state.percent_steps = !state.percent_steps;
+            effects.push(Effect::Info(if state.percent_steps {
+                format!("Adjusting values in steps of {:?} percent", PERCENT_STEPS)
+            } else {
+                format!("Adjusting values in steps of {:?}", STEPS)
+            }));
+        },
+        Command::TypeValue => {
+            if state.value_type.is_some() && has_selection {
+                state.entry = Some(Entry { kind: EntryKind::Value, text: String::new() });
+                effects.push(Effect::ShowSelected);
+                effects.push(Effect::Info("Enter value (decimal, 0x hex or %), <Enter> to stage, <Esc> to cancel".to_string()));
+            } else {
+                effects.push(Effect::Info("No LED or value selected".to_string()));
+            }
+        },
+        Command::Help => {
+            state.help = true;
+            effects.push(Effect::Redraw);
+        },
+        Command::Palette => {
+            state.entry = Some(Entry { kind: EntryKind::Command, text: String::new() });
+            effects.push(Effect::ShowSelected);
+        },
+        Command::LogOlder => effects.push(Effect::ScrollLog(1)),
+        Command::LogNewer => effects.push(Effect::ScrollLog(-1)),
+        Command::Exit => effects.push(Effect::Exit("User termination".to_string())),
+        Command::Apply => {
+            let mut info = "No LED or value selected, or value unchanged".to_string();
+            if has_selection {
+                if let Some(value_type) = state.value_type {
+                    if let Some(new_value) = state.new_value {
+                        info = format!("Setting {} {} to {}", describe_leds(&state.selected), value_type, new_value);
+                        effects.push(Effect::Request(Request::SetValue(state.selected.clone(), value_type, new_value)));
+                    }
+                    state.new_value = None;
+                    effects.push(Effect::ShowSelected);
+                }
+            }
+            effects.push(Effect::Info(info));
+        },
+    }
+
+    (state, effects)
+}
+
+fn refresh(effects: &mut Vec<Effect>) {
+    effects.push(Effect::Request(Request::Refresh));
+    effects.push(Effect::ShowSelected);
+    effects.push(Effect::Info("Refreshing LED status ... please wait".to_string()));
+}
+
+fn set_state(effects: &mut Vec<Effect>, leds: Vec<i32>, ledstate: LedState2) {
+    let info = format!("Setting {} to {}", describe_leds(&leds), ledstate);
+    effects.push(Effect::Request(Request::SetState(leds, ledstate.into())));
+    effects.push(Effect::ShowSelected);
+    effects.push(Effect::Info(info));
+}
+
+// Runs a command typed into the palette, in the same language as run-script,
+// e.g. pwm 3 128, state desk on or current 50%
+fn run_command(state: &mut State, effects: &mut Vec<Effect>, text: &str) -> Result<(), String> {
+    let cmd = match parse_line(text, &state.groups)? {
+        Some(cmd) => cmd,
+        None => return Ok(()),
+    };
+    match cmd {
+        Cmd::Select(leds) => select(state, effects, leds),
+        Cmd::State(leds, ledstate) => {
+            let leds = leds.unwrap_or_else(|| state.selected.clone());
+            if leds.is_empty() {
+                return Err("No LED selected".to_string());
+            }
+            set_state(effects, leds, ledstate.into());
+        },
+        Cmd::Value(leds, ty, val) => {
+            let leds = leds.unwrap_or_else(|| state.selected.clone());
+            if leds.is_empty() {
+                return Err("No LED selected".to_string());
+            }
+            if val > state.max_value(ty) {
+                return Err(format!("Value {} exceeds maximum {}", val, state.max_value(ty)));
+            }
+            let info = format!("Setting {} {} to {}", describe_leds(&leds), ty, val);
+            effects.push(Effect::Request(Request::SetValue(leds, ty, val)));
+            effects.push(Effect::ShowSelected);
+            effects.push(Effect::Info(info));
+        },
+        Cmd::Refresh => refresh(effects),
+        Cmd::Help => {
+            state.help = true;
+            effects.push(Effect::Redraw);
+        },
+        Cmd::Exit => effects.push(Effect::Exit("User termination".to_string())),
+        Cmd::Sleep(_) => return Err("sleep is only available in scripts".to_string()),
+        Cmd::Assert(_) => return Err("assert is only available in scripts".to_string()),
+    }
+    Ok(())
+}
+
+fn select(state: &mut State, effects: &mut Vec<Effect>, mut leds: Vec<i32>) {
+    leds.sort_unstable();
+    state.selected = leds;
+    let info = match state.selected.as_slice() {
+        [] => {
+            state.new_value = None;
+            state.value_type = None;
+            "No LED selected".to_string()
+        },
+        [led] => format!("Selected LED {}", led),
+        leds if leds.len() == NUM_LEDS => "Selected all LEDs".to_string(),
+        leds => format!("Selected LEDs {}", format_leds(leds)),
+    };
+    effects.push(Effect::ShowSelected);
+    effects.push(Effect::Info(info));
+}
+
+// Entry modes - keys edit the value or LEDs being typed until they are
+// accepted with Enter or abandoned with Esc
+fn process_entry(state: &mut State, effects: &mut Vec<Effect>, key: Key) {
+    let entry = state.entry.as_mut().unwrap();
+    let (max_len, valid): (usize, fn(char) -> bool) = match entry.kind {
+        EntryKind::Value => (MAX_VALUE_ENTRY, |ch| ch.is_ascii_hexdigit() || "xX%.".contains(ch)),
+        EntryKind::Leds => (MAX_LEDS_ENTRY, |ch| ch.is_ascii_alphanumeric() || ",-_".contains(ch)),
+        EntryKind::Command => (MAX_COMMAND_ENTRY, |ch| ch.is_ascii_graphic() || ch == ' '),
+    };
+    match key {
+        Key::Char(ch) if entry.text.len() < max_len && valid(ch) => entry.text.push(ch),
+        Key::Backspace | Key::Delete => {
+            entry.text.pop();
+        },
+        Key::Enter => {
+            let text = entry.text.trim().to_string();
+            match entry.kind {
+                EntryKind::Value => match parse_value(&text, state.max_value(state.value_type.unwrap())) {
+                    Ok(val) => {
+                        state.new_value = Some(val);
+                        state.entry = None;
+                        effects.push(Effect::Info(format!("Staged value {}, <space> to apply", val)));
+                    },
+                    Err(err) => effects.push(Effect::Info(err)),
+                },
+                EntryKind::Leds => match state.parse_selection(&text) {
+                    Ok(leds) => {
+                        state.entry = None;
+                        select(state, effects, leds);
+                    },
+                    Err(err) => effects.push(Effect::Info(err)),
+                },
+                EntryKind::Command => {
+                    state.entry = None;
+                    if let Err(err) = run_command(state, effects, &text) {
+                        effects.push(Effect::Info(err));
+                    }
+                },
+            }
+        },
+        Key::Esc => {
+            state.entry = None;
+            effects.push(Effect::Info("Cancelled entry".to_string()));
+        },
+        _ => (),
+    }
+    effects.insert(0, Effect::ShowSelected);
+}
+
+fn to_percent(val: u32) -> i32 {
+    ((val * 100 + MAX_VALUE / 2) / MAX_VALUE) as i32
+}
+
+fn from_percent(pct: i32) -> u32 {
+    (pct as u32 * MAX_VALUE + 50) / 100
+}
+
+// Adjusts the new value, starting from the current one if none is staged
+fn adjust(state: &mut State, effects: &mut Vec<Effect>, adj: Adjust) {
+    let value_type = match state.value_type {
+        Some(x) if !state.selected.is_empty() => x,
+        _ => {
+            effects.push(Effect::Info("No LED or value selected".to_string()));
+            return;
+        },
+    };
+    let max = state.max_value(value_type);
+    // With several LEDs selected, adjustment starts from the first one's value
+    let current = state.new_value.or_else(|| get_value(&state.info, &value_type, state.selected[0]));
+    let (sign, size) = match adj {
+        Adjust::To(val) => {
+            let val = val.min(max);
+            state.new_value = Some(val);
+            effects.push(Effect::ShowSelected);
+            effects.push(Effect::Info(format!("Set value to {}", val)));
+            return;
+        },
+        Adjust::Up(size) => (1, size),
+        Adjust::Down(size) => (-1, size),
+    };
+    state.new_value = current.map(|val| {
+        if state.percent_steps {
+            let pct = to_percent(val) + sign * PERCENT_STEPS[size];
+            from_percent(pct.clamp(0, 100))
+        } else {
+            (val as i32 + sign * STEPS[size]).clamp(0, MAX_VALUE as i32) as u32
+        }
+    }).map(|val| val.min(max));
+    effects.push(Effect::ShowSelected);
+    effects.push(Effect::Info(if sign > 0 { "Incremented value" } else { "Decremented value" }.to_string()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pca9956b_api::models::LedError;
+    use crate::led::NUM_LEDS;
+
+    fn info(pwm: u32, current: u32) -> Vec<LedInfo> {
+        (0..NUM_LEDS as i32)
+            .map(|ii| LedInfo {
+                index: Some(ii as u32),
+                state: Some(LedState::PWM),
+                pwm: Some(pwm),
+                current: Some(current),
+                error: Some(LedError::NONE),
+            })
+            .collect()
+    }
+
+    fn press(state: State, keys: &[Key]) -> (State, Vec<Effect>) {
+        keys.iter().fold((state, vec![]), |(state, _), key| process_input(state, *key))
+    }
+
+    fn requests(effects: &[Effect]) -> Vec<Request> {
+        effects.iter()
+            .filter_map(|x| match x {
+                Effect::Request(req) => Some(req.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn selects_leds() {
+        let (state, effects) = press(State::default(), &[Key::Char('q')]);
+        assert_eq!(state.selected, vec![0]);
+        assert!(effects.contains(&Effect::Info("Selected LED 0".to_string())));
+        assert!(effects.contains(&Effect::ShowSelected));
+
+        let (state, _) = press(state, &[Key::Char(',')]);
+        assert_eq!(state.selected, vec![23]);
+        let (state, effects) = press(state, &[Key::Char('o')]);
+        assert_eq!(state.selected, (0..24).collect::<Vec<_>>());
+        assert!(effects.contains(&Effect::Info("Selected all LEDs".to_string())));
+    }
+
+    #[test]
+    fn toggles_leds() {
+        let (state, _) = press(State::default(), &[Key::Char('r'), Key::Char('Q'), Key::Char('<')]);
+        assert_eq!(state.selected, vec![0, 3, 23]);
+        let (state, effects) = press(state, &[Key::Char('R')]);
+        assert_eq!(state.selected, vec![0, 23]);
+        assert!(effects.contains(&Effect::Info("Selected LEDs 0,23".to_string())));
+        let (state, _) = press(state, &[Key::Char('Q'), Key::Char('<')]);
+        assert!(state.selected.is_empty());
+    }
+
+    #[test]
+    fn selects_rows() {
+        let (state, _) = press(State::default(), &[Key::Alt('s')]);
+        assert_eq!(state.selected, (8..16).collect::<Vec<_>>());
+        let (state, effects) = press(state, &[Key::Alt(',')]);
+        assert_eq!(state.selected, (16..24).collect::<Vec<_>>());
+        assert!(effects.contains(&Effect::Info("Selected LEDs 16-23".to_string())));
+    }
+
+    fn select_typed(state: State, text: &str) -> (State, Vec<Effect>) {
+        let mut keys = vec![Key::Char('#')];
+        keys.extend(text.chars().map(Key::Char));
+        keys.push(Key::Enter);
+        press(state, &keys)
+    }
+
+    #[test]
+    fn selects_typed_ranges_and_groups() {
+        let state = State { groups: vec![("desk".to_string(), vec![20, 21])], ..Default::default() };
+        let (state, _) = select_typed(state, "4-11");
+        assert_eq!(state.entry, None);
+        assert_eq!(state.selected, (4..12).collect::<Vec<_>>());
+        let (state, _) = select_typed(state, "desk,1,3-4");
+        assert_eq!(state.selected, vec![1, 3, 4, 20, 21]);
+        let (state, effects) = select_typed(state, "lounge");
+        assert!(state.entry.is_some());
+        assert_eq!(state.selected, vec![1, 3, 4, 20, 21]);
+        assert!(effects.contains(&Effect::Info("Invalid LEDs or group lounge".to_string())));
+    }
+
+    #[test]
+    fn deselecting_clears_value() {
+        let state = State { info: info(10, 20), ..Default::default() };
+        let (state, _) = press(state, &[Key::Char('w'), Key::Char('6'), Key::Up]);
+        assert_eq!(state.value_type, Some(ValueType::Pwm));
+        assert_eq!(state.new_value, Some(11));
+
+        let (state, effects) = press(state, &[Key::Char('p')]);
+        assert!(state.selected.is_empty());
+        assert_eq!(state.value_type, None);
+        assert_eq!(state.new_value, None);
+        assert!(effects.contains(&Effect::Info("No LED selected".to_string())));
+    }
+
+    #[test]
+    fn value_type_needs_selection() {
+        let (state, effects) = press(State::default(), &[Key::Char('5')]);
+        assert_eq!(state.value_type, None);
+        assert!(effects.contains(&Effect::Info("No LED selected".to_string())));
+    }
+
+    #[test]
+    fn increments_from_current_value() {
+        let state = State { info: info(10, 20), ..Default::default() };
+        let (state, _) = press(state, &[Key::Char('q'), Key::Char('5'), Key::Up, Key::Up]);
+        assert_eq!(state.new_value, Some(22));
+        let (state, _) = press(state, &[Key::Down]);
+        assert_eq!(state.new_value, Some(21));
+    }
+
+    #[test]
+    fn increment_stops_at_255() {
+        let state = State { info: info(254, 0), ..Default::default() };
+        let (state, _) = press(state, &[Key::Char('q'), Key::Char('6'), Key::Up, Key::Up, Key::Up]);
+        assert_eq!(state.new_value, Some(255));
+    }
+
+    #[test]
+    fn decrement_stops_at_0() {
+        let state = State { info: info(1, 0), ..Default::default() };
+        let (state, _) = press(state, &[Key::Char('q'), Key::Char('6'), Key::Down, Key::Down, Key::Down]);
+        assert_eq!(state.new_value, Some(0));
+    }
+
+    #[test]
+    fn coarse_adjustment() {
+        let state = State { info: info(100, 0), ..Default::default() };
+        let (state, _) = press(state, &[Key::Char('q'), Key::Char('6'), Key::PageUp]);
+        assert_eq!(state.new_value, Some(116));
+        let (state, _) = press(state, &[Key::ShiftDown, Key::ShiftDown]);
+        assert_eq!(state.new_value, Some(96));
+        let (state, _) = press(state, &[Key::PageUp; 10]);
+        assert_eq!(state.new_value, Some(255));
+        let (state, _) = press(state, &[Key::ShiftDown; 30]);
+        assert_eq!(state.new_value, Some(0));
+    }
+
+    #[test]
+    fn home_and_end() {
+        let state = State { info: info(100, 0), ..Default::default() };
+        let (state, _) = press(state, &[Key::Char('q'), Key::Char('6'), Key::End]);
+        assert_eq!(state.new_value, Some(255));
+        let (state, _) = press(state, &[Key::Home]);
+        assert_eq!(state.new_value, Some(0));
+    }
+
+    #[test]
+    fn percent_steps() {
+        let state = State { info: info(128, 0), ..Default::default() };
+        let (state, _) = press(state, &[Key::Char('q'), Key::Char('6'), Key::Char('%'), Key::Up]);
+        assert!(state.percent_steps);
+        assert_eq!(state.new_value, Some(130)); // 50% + 1%
+        let (state, _) = press(state, &[Key::PageUp; 10]);
+        assert_eq!(state.new_value, Some(255));
+        let (state, _) = press(state, &[Key::ShiftDown]);
+        assert_eq!(state.new_value, Some(242)); // 95%
+        let (state, _) = press(state, &[Key::Char('%'), Key::Down]);
+        assert!(!state.percent_steps);
+        assert_eq!(state.new_value, Some(241));
+    }
+
+    #[test]
+    fn adjust_respects_max_current() {
+        let state = State { info: info(0, 100), max_current: 120, ..Default::default() };
+        let (state, _) = press(state, &[Key::Char('q'), Key::Char('5'), Key::PageUp, Key::PageUp]);
+        assert_eq!(state.new_value, Some(120));
+        let (state, _) = press(state, &[Key::End]);
+        assert_eq!(state.new_value, Some(120));
+    }
+
+    fn enter_value(state: State, text: &str) -> (State, Vec<Effect>) {
+        let mut keys = vec![Key::Char('=')];
+        keys.extend(text.chars().map(Key::Char));
+        keys.push(Key::Enter);
+        press(state, &keys)
+    }
+
+    #[test]
+    fn entry_stages_value() {
+        let (state, _) = press(State::default(), &[Key::Char('q'), Key::Char('6')]);
+        let (state, _) = enter_value(state, "200");
+        assert_eq!(state.entry, None);
+        assert_eq!(state.new_value, Some(200));
+        let (state, _) = enter_value(state, "0x1f");
+        assert_eq!(state.new_value, Some(31));
+        let (state, _) = enter_value(state, "50%");
+        assert_eq!(state.new_value, Some(128));
+        let (_, effects) = press(state, &[Key::Char(' ')]);
+        assert_eq!(requests(&effects), vec![Request::SetValue(vec![0], ValueType::Pwm, 128)]);
+    }
+
+    #[test]
+    fn entry_rejects_invalid_values() {
+        let state = State { max_current: 100, ..Default::default() };
+        let (state, _) = press(state, &[Key::Char('q'), Key::Char('5')]);
+        let (state, effects) = enter_value(state, "101");
+        assert_eq!(state.entry.as_ref().map(|x| x.text.as_str()), Some("101"));
+        assert_eq!(state.new_value, None);
+        assert!(effects.contains(&Effect::Info("Value 101 exceeds maximum 100".to_string())));
+
+        let (state, _) = press(state, &[Key::Backspace, Key::Backspace, Key::Enter]);
+        assert_eq!(state.entry, None);
+        assert_eq!(state.new_value, Some(1));
+
+        let (state, effects) = enter_value(state, "0x");
+        assert_eq!(state.entry.as_ref().map(|x| x.text.as_str()), Some("0x"));
+        assert!(effects.contains(&Effect::Info("Invalid value 0x".to_string())));
+    }
+
+    #[test]
+    fn entry_cancels_with_esc() {
+        let (state, _) = press(State::default(), &[Key::Char('q'), Key::Char('6')]);
+        let (state, effects) = press(state, &[Key::Char('='), Key::Char('1'), Key::Char('q'), Key::Esc]);
+        assert_eq!(state.entry, None);
+        assert_eq!(state.new_value, None);
+        assert_eq!(state.selected, vec![0]);
+        assert!(!effects.iter().any(|x| matches!(x, Effect::Exit(_))));
+    }
+
+    #[test]
+    fn adjust_needs_value_type() {
+        let state = State { info: info(10, 20), ..Default::default() };
+        let (state, effects) = press(state, &[Key::Char('q'), Key::Up]);
+        assert_eq!(state.new_value, None);
+        assert!(effects.contains(&Effect::Info("No LED or value selected".to_string())));
+        assert!(!effects.contains(&Effect::ShowSelected));
+    }
+
+    #[test]
+    fn apply_writes_new_value() {
+        let state = State { info: info(10, 20), ..Default::default() };
+        let (state, effects) = press(state, &[Key::Char('e'), Key::Char('6'), Key::Up, Key::Char(' ')]);
+        assert_eq!(requests(&effects), vec![Request::SetValue(vec![2], ValueType::Pwm, 11)]);
+        assert_eq!(state.new_value, None);
+        assert_eq!(state.value_type, Some(ValueType::Pwm));
+    }
+
+    #[test]
+    fn apply_without_new_value_does_nothing() {
+        let state = State { info: info(10, 20), ..Default::default() };
+        let (_, effects) = press(state, &[Key::Char('e'), Key::Char('6'), Key::Char(' ')]);
+        assert!(requests(&effects).is_empty());
+    }
+
+    #[test]
+    fn apply_writes_whole_selection() {
+        let state = State { info: info(10, 20), ..Default::default() };
+        let (_, effects) = press(state, &[Key::Char('e'), Key::Char('T'), Key::Char('6'), Key::Up, Key::Char(' ')]);
+        assert_eq!(requests(&effects), vec![Request::SetValue(vec![2, 4], ValueType::Pwm, 11)]);
+        assert!(effects.contains(&Effect::Info("Setting 2 LEDs PWM to 11".to_string())));
+    }
+
+    #[test]
+    fn mode_sets_selected_led() {
+        let (_, effects) = press(State::default(), &[Key::Char('q'), Key::Char('3')]);
+        assert_eq!(requests(&effects), vec![Request::SetState(vec![0], LedState::PWM)]);
+    }
+
+    #[test]
+    fn mode_sets_selected_set() {
+        let (_, effects) = press(State::default(), &[Key::Alt('a'), Key::Char('Z'), Key::Char('2')]);
+        assert_eq!(requests(&effects), vec![Request::SetState((8..17).collect(), LedState::TRUE)]);
+    }
+
+    #[test]
+    fn mode_sets_all_leds_when_global() {
+        let (_, effects) = press(State::default(), &[Key::Char('o'), Key::Char('1')]);
+        assert_eq!(requests(&effects), vec![Request::SetState((0..24).collect(), LedState::FALSE)]);
+    }
+
+    #[test]
+    fn mode_needs_selection() {
+        let (_, effects) = press(State::default(), &[Key::Char('2')]);
+        assert!(effects.is_empty());
+    }
+
+    #[test]
+    fn enter_refreshes_and_esc_exits() {
+        let (_, effects) = press(State::default(), &[Key::Enter]);
+        assert_eq!(requests(&effects), vec![Request::Refresh]);
+        let (_, effects) = press(State::default(), &[Key::Esc]);
+        assert_eq!(effects, vec![Effect::Exit("User termination".to_string())]);
+    }
+
+    #[test]
+    fn any_key_closes_help() {
+        let (state, effects) = press(State::default(), &[Key::Char('?')]);
+        assert!(state.help);
+        assert_eq!(effects, vec![Effect::Redraw]);
+        let (state, effects) = press(state, &[Key::Char('q')]);
+        assert!(!state.help);
+        assert!(state.selected.is_empty());
+        assert_eq!(effects, vec![Effect::Redraw]);
+    }
+
+    fn run_command(state: State, text: &str) -> (State, Vec<Effect>) {
+        let mut keys = vec![Key::Char(':')];
+        keys.extend(text.chars().map(Key::Char));
+        keys.push(Key::Enter);
+        press(state, &keys)
+    }
+
+    #[test]
+    fn palette_runs_commands() {
+        let state = State { groups: vec![("desk".to_string(), vec![4, 5])], ..Default::default() };
+        let (state, effects) = run_command(state, "pwm 3 128");
+        assert_eq!(requests(&effects), vec![Request::SetValue(vec![3], ValueType::Pwm, 128)]);
+        assert!(state.entry.is_none());
+        let (state, effects) = run_command(state, "state desk,7 on");
+        assert_eq!(requests(&effects), vec![Request::SetState(vec![4, 5, 7], LedState::TRUE)]);
+        let (state, _) = run_command(state, "select 1 2 3");
+        assert_eq!(state.selected, vec![1, 2, 3]);
+        let (state, _) = run_command(state, "select 1-2");
+        assert_eq!(state.selected, vec![1, 2]);
+        let (_, effects) = run_command(state, "current 50%");
+        assert_eq!(requests(&effects), vec![Request::SetValue(vec![1, 2], ValueType::Current, 128)]);
+    }
+
+    #[test]
+    fn palette_reports_errors() {
+        let (_, effects) = run_command(State::default(), "pwm 128");
+        assert!(effects.contains(&Effect::Info("No LED selected".to_string())));
+        let (_, effects) = run_command(State::default(), "frob");
+        assert!(effects.contains(&Effect::Info("Unknown command frob".to_string())));
+        let (_, effects) = run_command(State::default(), "sleep 10");
+        assert!(effects.contains(&Effect::Info("sleep is only available in scripts".to_string())));
+        let state = State { max_current: 100, ..Default::default() };
+        let (_, effects) = run_command(state, "current 2 200");
+        assert!(requests(&effects).is_empty());
+        assert!(effects.contains(&Effect::Info("Value 200 exceeds maximum 100".to_string())));
+    }
+
+    #[test]
+    fn brackets_scroll_log() {
+        let (state, effects) = press(State::default(), &[Key::Char('[')]);
+        assert_eq!(effects, vec![Effect::ScrollLog(1)]);
+        let (_, effects) = press(state, &[Key::Char(']')]);
+        assert_eq!(effects, vec![Effect::ScrollLog(-1)]);
+    }
+}
@@ -0,0 +1,496 @@
+// Key bindings for the TUI.  A Keymap maps each key to the Command it runs,
+// starting from a preset for the keyboard layout, optionally overridden from
+// a JSON file such as:
+//
+//   { "preset": "azerty", "bindings": { "apply": "enter", "refresh": ["F5", "F6"] } }
+//
+// Each Command named in the file loses its preset keys, and each key named
+// is taken from whatever Command it was bound to.  The same table drives both
+// the TUI's input processing and its help text.
+
+use std::collections::BTreeMap;
+use serde::Deserialize;
+use crate::input::{PERCENT_STEPS, STEPS};
+use crate::led::{LedState2, ValueType, NUM_LEDS};
+
+// LEDs on each row of the keyboard layout
+pub const ROW_LEDS: i32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Key {
+    Char(char),
+    Alt(char),
+    Enter,
+    Esc,
+    Up,
+    Down,
+    Left,
+    Right,
+    ShiftUp,
+    ShiftDown,
+    ShiftLeft,
+    ShiftRight,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Insert,
+    Delete,
+    Backspace,
+    Tab,
+    BackTab,
+    F(u8),
+    Resize,
+    Other(i32),
+}
+
+// Named keys, as written in keymap files and shown in help
+const KEY_NAMES: [(Key, &str); 20] = [
+    (Key::Char(' '), "Space"),
+    (Key::Enter, "Enter"),
+    (Key::Esc, "Esc"),
+    (Key::Up, "Up"),
+    (Key::Down, "Down"),
+    (Key::Left, "Left"),
+    (Key::Right, "Right"),
+    (Key::ShiftUp, "S-Up"),
+    (Key::ShiftDown, "S-Down"),
+    (Key::ShiftLeft, "S-Left"),
+    (Key::ShiftRight, "S-Right"),
+    (Key::PageUp, "PgUp"),
+    (Key::PageDown, "PgDn"),
+    (Key::Home, "Home"),
+    (Key::End, "End"),
+    (Key::Insert, "Ins"),
+    (Key::Delete, "Del"),
+    (Key::Backspace, "BS"),
+    (Key::Tab, "Tab"),
+    (Key::BackTab, "S-Tab"),
+];
+
+impl std::fmt::Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some((_, name)) = KEY_NAMES.iter().find(|(key, _)| key == self) {
+            return write!(f, "{}", name);
+        }
+        match self {
+            Key::Char(ch) => write!(f, "{}", ch),
+            Key::Alt(ch) => write!(f, "M-{}", ch),
+            Key::F(n) => write!(f, "F{}", n),
+            Key::Resize => write!(f, "Resize"),
+            Key::Other(ch) => write!(f, "#{}", ch),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl std::str::FromStr for Key {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((key, _)) = KEY_NAMES.iter().find(|(_, name)| name.eq_ignore_ascii_case(s)) {
+            return Ok(*key);
+        }
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(ch), None) => return Ok(Key::Char(ch)),
+            (None, _) => return Err("Empty key name".to_string()),
+            _ => (),
+        }
+        if let Some(rest) = s.strip_prefix("M-") {
+            let mut chars = rest.chars();
+            if let (Some(ch), None) = (chars.next(), chars.next()) {
+                return Ok(Key::Alt(ch));
+            }
+        }
+        match s.strip_prefix('F').or_else(|| s.strip_prefix('f')).map(|x| x.parse::<u8>()) {
+            Some(Ok(n)) if (1..=12).contains(&n) => Ok(Key::F(n)),
+            _ => Err(format!("Invalid key {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Refresh,
+    Exit,
+    SelectNone,
+    SelectAll,
+    SelectLed(i32),
+    ToggleLed(i32),
+    SelectRow(i32),
+    TypeLeds,
+    Mode(LedState2),
+    Value(ValueType),
+    // Adjust the new value by a fine (0), medium (1) or coarse (2) step
+    Up(usize),
+    Down(usize),
+    Min,
+    Max,
+    PercentSteps,
+    TypeValue,
+    Apply,
+    Help,
+    Palette,
+    // Scroll the activity log back to older entries, or forward again
+    LogOlder,
+    LogNewer,
+}
+
+const MODES: [LedState2; 4] = [LedState2::Off, LedState2::On, LedState2::Pwm, LedState2::PwmPlus];
+const VALUES: [ValueType; 2] = [ValueType::Current, ValueType::Pwm];
+const STEP_NAMES: [&str; 3] = ["", "-medium", "-coarse"];
+
+impl Command {
+    // Every Command, in the order they're listed in help
+    pub fn all() -> Vec<Command> {
+        let mut all = vec![Command::SelectNone, Command::SelectAll, Command::TypeLeds];
+        all.extend((0..NUM_LEDS as i32).map(Command::SelectLed));
+        all.extend((0..NUM_LEDS as i32).map(Command::ToggleLed));
+        all.extend((0..NUM_LEDS as i32 / ROW_LEDS).map(Command::SelectRow));
+        all.extend(MODES.iter().map(|x| Command::Mode(*x)));
+        all.extend(VALUES.iter().map(|x| Command::Value(*x)));
+        for size in 0..STEP_NAMES.len() {
+            all.push(Command::Up(size));
+            all.push(Command::Down(size));
+        }
+        all.extend(vec![
+            Command::Min,
+            Command::Max,
+            Command::PercentSteps,
+            Command::TypeValue,
+            Command::Apply,
+            Command::Refresh,
+            Command::LogOlder,
+            Command::LogNewer,
+            Command::Help,
+            Command::Palette,
+            Command::Exit,
+        ]);
+        all
+    }
+
+    // The Command's name in keymap files
+    pub fn name(&self) -> String {
+        match self {
+            Command::Refresh => "refresh".to_string(),
+            Command::Exit => "exit".to_string(),
+            Command::SelectNone => "select-none".to_string(),
+            Command::SelectAll => "select-all".to_string(),
+            Command::SelectLed(led) => format!("select-led-{}", led),
+            Command::ToggleLed(led) => format!("toggle-led-{}", led),
+            Command::SelectRow(row) => format!("select-row-{}", row),
+            Command::TypeLeds => "type-leds".to_string(),
+            Command::Mode(state) => format!("mode-{}", state.to_string().to_lowercase()),
+            Command::Value(ty) => format!("{}-value", ty.to_string().to_lowercase()),
+            Command::Up(size) => format!("up{}", STEP_NAMES[*size]),
+            Command::Down(size) => format!("down{}", STEP_NAMES[*size]),
+            Command::Min => "min".to_string(),
+            Command::Max => "max".to_string(),
+            Command::PercentSteps => "percent-steps".to_string(),
+            Command::TypeValue => "type-value".to_string(),
+            Command::Apply => "apply".to_string(),
+            Command::Help => "help".to_string(),
+            Command::Palette => "palette".to_string(),
+            Command::LogOlder => "log-older".to_string(),
+            Command::LogNewer => "log-newer".to_string(),
+        }
+    }
+
+    // What the Command does, short enough for the help overlay's columns
+    pub fn description(&self) -> String {
+        match self {
+            Command::Refresh => "Refresh LED status".to_string(),
+            Command::Exit => "Exit".to_string(),
+            Command::SelectNone => "Select no LEDs".to_string(),
+            Command::SelectAll => "Select all LEDs".to_string(),
+            Command::SelectLed(led) => format!("Select LED {}", led),
+            Command::ToggleLed(led) => format!("Toggle LED {}", led),
+            Command::SelectRow(row) => format!("Select LEDs {}-{}", row * ROW_LEDS, (row + 1) * ROW_LEDS - 1),
+            Command::TypeLeds => "Type LEDs to select".to_string(),
+            Command::Mode(state) => format!("Set LEDs to {}", state),
+            Command::Value(ty) => format!("Modify {} value", ty),
+            Command::Up(size) => format!("Step up by {} (or {}%)", STEPS[*size], PERCENT_STEPS[*size]),
+            Command::Down(size) => format!("Step down by {} (or {}%)", STEPS[*size], PERCENT_STEPS[*size]),
+            Command::Min => "Set value to 0".to_string(),
+            Command::Max => "Set value to maximum".to_string(),
+            Command::PercentSteps => "Toggle percent steps".to_string(),
+            Command::TypeValue => "Type a value".to_string(),
+            Command::Apply => "Apply staged value".to_string(),
+            Command::Help => "Show key bindings".to_string(),
+            Command::Palette => "Type a command".to_string(),
+            Command::LogOlder => "Scroll log back".to_string(),
+            Command::LogNewer => "Scroll log forward".to_string(),
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        Command::all().into_iter()
+            .find(|x| x.name() == name)
+            .ok_or_else(|| format!("Unknown command {}", name))
+    }
+}
+
+// A preset's keys for selecting each LED, and those keys shifted
+struct Preset {
+    name: &'static str,
+    leds: &'static str,
+    shifted: &'static str,
+    none: char,
+    all: char,
+    help: char,
+    palette: char,
+}
+
+// The LED keys keep their positions on the keyboard across layouts, so help
+// and the command palette move to keys those leave free
+const PRESETS: [Preset; 3] = [
+    Preset {
+        name: "qwerty",
+        leds: "qwertyuiasdfghjkzxcvbnm,",
+        shifted: "QWERTYUIASDFGHJKZXCVBNM<",
+        none: 'p',
+        all: 'o',
+        help: '?',
+        palette: ':',
+    },
+    Preset {
+        name: "azerty",
+        leds: "azertyuiqsdfghjkwxcvbn,;",
+        shifted: "AZERTYUIQSDFGHJKWXCVBN?.",
+        none: 'p',
+        all: 'o',
+        help: '!',
+        palette: ':',
+    },
+    Preset {
+        name: "dvorak",
+        leds: "',.pyfgcaoeuidht;qjkxbmw",
+        shifted: "\"<>PYFGCAOEUIDHT:QJKXBMW",
+        none: 'l',
+        all: 'r',
+        help: '?',
+        palette: '/',
+    },
+];
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Keys {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct KeymapFile {
+    preset: Option<String>,
+    #[serde(default)]
+    bindings: BTreeMap<String, Keys>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    bindings: Vec<(Key, Command)>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap::preset("qwerty").unwrap()
+    }
+}
+
+impl Keymap {
+    pub fn presets() -> Vec<&'static str> {
+        PRESETS.iter().map(|x| x.name).collect()
+    }
+
+    pub fn preset(name: &str) -> Result<Self, String> {
+        let preset = PRESETS.iter()
+            .find(|x| x.name == name)
+            .ok_or_else(|| format!("Unknown keymap preset {}, expected one of {}", name, Keymap::presets().join(", ")))?;
+        let mut bindings = vec![
+            (Key::Char(preset.none), Command::SelectNone),
+            (Key::Char(preset.all), Command::SelectAll),
+            (Key::Char('#'), Command::TypeLeds),
+        ];
+        for (led, (ch, shifted)) in preset.leds.chars().zip(preset.shifted.chars()).enumerate() {
+            let led = led as i32;
+            bindings.push((Key::Char(ch), Command::SelectLed(led)));
+            bindings.push((Key::Char(shifted), Command::ToggleLed(led)));
+            bindings.push((Key::Alt(ch), Command::SelectRow(led / ROW_LEDS)));
+        }
+        bindings.extend(MODES.iter().zip("1234".chars()).map(|(x, ch)| (Key::Char(ch), Command::Mode(*x))));
+        bindings.extend(VALUES.iter().zip("56".chars()).map(|(x, ch)| (Key::Char(ch), Command::Value(*x))));
+        bindings.extend(vec![
+            (Key::Up, Command::Up(0)),
+            (Key::Down, Command::Down(0)),
+            (Key::ShiftUp, Command::Up(1)),
+            (Key::ShiftDown, Command::Down(1)),
+            (Key::PageUp, Command::Up(2)),
+            (Key::PageDown, Command::Down(2)),
+            (Key::Home, Command::Min),
+            (Key::End, Command::Max),
+            (Key::Char('%'), Command::PercentSteps),
+            (Key::Char('='), Command::TypeValue),
+            (Key::Char(' '), Command::Apply),
+            (Key::Enter, Command::Refresh),
+            (Key::Char(preset.help), Command::Help),
+            (Key::F(1), Command::Help),
+            (Key::Char(preset.palette), Command::Palette),
+            (Key::Char('['), Command::LogOlder),
+            (Key::Char(']'), Command::LogNewer),
+            (Key::Esc, Command::Exit),
+        ]);
+        Ok(Keymap { bindings })
+    }
+
+    // Loads a preset by name, or a keymap file
+    pub fn load(spec: &str) -> Result<Self, String> {
+        if Keymap::presets().contains(&spec) {
+            return Keymap::preset(spec);
+        }
+        let text = std::fs::read_to_string(spec).map_err(|e| format!("Failed to read keymap {}: {}", spec, e))?;
+        let file: KeymapFile = serde_json::from_str(&text).map_err(|e| format!("Invalid keymap {}: {}", spec, e))?;
+        let mut keymap = Keymap::preset(file.preset.as_deref().unwrap_or("qwerty"))?;
+        for (name, keys) in file.bindings {
+            let keys = match keys {
+                Keys::One(x) => vec![x],
+                Keys::Many(x) => x,
+            };
+            let keys = keys.iter().map(|x| x.parse::<Key>()).collect::<Result<Vec<_>, _>>()?;
+            keymap.bind(Command::from_name(&name)?, &keys);
+        }
+        Ok(keymap)
+    }
+
+    // Replaces the keys for a Command
+    pub fn bind(&mut self, cmd: Command, keys: &[Key]) {
+        self.bindings.retain(|(key, x)| *x != cmd && !keys.contains(key));
+        self.bindings.extend(keys.iter().map(|key| (*key, cmd)));
+    }
+
+    pub fn command(&self, key: Key) -> Option<Command> {
+        self.bindings.iter().find(|(x, _)| *x == key).map(|(_, cmd)| *cmd)
+    }
+
+    pub fn keys(&self, cmd: Command) -> Vec<Key> {
+        self.bindings.iter().filter(|(_, x)| *x == cmd).map(|(key, _)| *key).collect()
+    }
+
+    // The first key for a Command, for help text
+    pub fn key_name(&self, cmd: Command) -> String {
+        match self.keys(cmd).first() {
+            Some(key) => key.to_string(),
+            None => "-".to_string(),
+        }
+    }
+
+    // Every key binding with its description, for the help overlay.  LEDs
+    // are listed a keyboard row at a time, and steps up and down, min and max
+    // and log scrolling in pairs, so that all fit on the smallest screen.
+    pub fn help(&self) -> Vec<(String, String)> {
+        let names = |cmd| match self.keys(cmd).as_slice() {
+            [] => "-".to_string(),
+            keys => keys.iter().map(|x| x.to_string()).collect::<Vec<_>>().join("/"),
+        };
+        let rows = |row_cmd: fn(i32) -> Command, rows: std::ops::Range<i32>| {
+            rows.map(|x| names(row_cmd(x))).collect::<Vec<_>>().join(" ")
+        };
+        let mut help = vec![];
+        for row in 0..NUM_LEDS as i32 / ROW_LEDS {
+            let leds = row * ROW_LEDS..(row + 1) * ROW_LEDS;
+            help.push((rows(Command::SelectLed, leds.clone()), format!("Select LEDs {}-{}", leds.start, leds.end - 1)));
+        }
+        for row in 0..NUM_LEDS as i32 / ROW_LEDS {
+            let leds = row * ROW_LEDS..(row + 1) * ROW_LEDS;
+            help.push((rows(Command::ToggleLed, leds.clone()), format!("Toggle LEDs {}-{}", leds.start, leds.end - 1)));
+        }
+        // Each row has a key per LED, so only the first is shown
+        let row_keys = (0..NUM_LEDS as i32 / ROW_LEDS).map(|x| self.key_name(Command::SelectRow(x)));
+        help.push((row_keys.collect::<Vec<_>>().join(" "), "Select a row of LEDs".to_string()));
+        for cmd in Command::all() {
+            match cmd {
+                Command::SelectLed(_) | Command::ToggleLed(_) | Command::SelectRow(_) | Command::Down(_) | Command::Max | Command::LogNewer => (),
+                Command::Up(size) => help.push((
+                    format!("{} {}", names(cmd), names(Command::Down(size))),
+                    format!("Step by {} (or {}%)", STEPS[size], PERCENT_STEPS[size]),
+                )),
+                Command::Min => help.push((
+                    format!("{} {}", names(cmd), names(Command::Max)),
+                    "Set value to 0 or max".to_string(),
+                )),
+                Command::LogOlder => help.push((
+                    format!("{} {}", names(cmd), names(Command::LogNewer)),
+                    "Scroll activity log".to_string(),
+                )),
+                _ => help.push((names(cmd), cmd.description())),
+            }
+        }
+        help
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_names() {
+        for name in &["q", ",", "Space", "Enter", "S-Up", "PgDn", "M-q", "F5"] {
+            assert_eq!(name.parse::<Key>().unwrap().to_string(), *name);
+        }
+        assert_eq!("pgup".parse::<Key>(), Ok(Key::PageUp));
+        assert!("F13".parse::<Key>().is_err());
+        assert!("qq".parse::<Key>().is_err());
+    }
+
+    #[test]
+    fn command_names() {
+        for cmd in Command::all() {
+            assert_eq!(Command::from_name(&cmd.name()), Ok(cmd));
+        }
+        assert_eq!(Command::Value(ValueType::Pwm).name(), "pwm-value");
+        assert_eq!(Command::Mode(LedState2::PwmPlus).name(), "mode-pwmplus");
+    }
+
+    #[test]
+    fn presets_bind_every_led() {
+        for name in Keymap::presets() {
+            let keymap = Keymap::preset(name).unwrap();
+            for cmd in Command::all() {
+                assert!(!keymap.keys(cmd).is_empty(), "{} has no key for {}", name, cmd.name());
+            }
+            for (key, cmd) in &keymap.bindings {
+                assert_eq!(keymap.command(*key), Some(*cmd), "{} binds {} twice", name, key);
+            }
+        }
+    }
+
+    #[test]
+    fn azerty() {
+        let keymap = Keymap::preset("azerty").unwrap();
+        assert_eq!(keymap.command(Key::Char('a')), Some(Command::SelectLed(0)));
+        assert_eq!(keymap.command(Key::Char('W')), Some(Command::ToggleLed(16)));
+        assert_eq!(keymap.command(Key::Alt('q')), Some(Command::SelectRow(1)));
+    }
+
+    #[test]
+    fn help_lists_bindings() {
+        let help = Keymap::default().help();
+        assert_eq!(help[0], ("q w e r t y u i".to_string(), "Select LEDs 0-7".to_string()));
+        assert_eq!(help[6], ("M-q M-a M-z".to_string(), "Select a row of LEDs".to_string()));
+        assert!(help.contains(&("?/F1".to_string(), "Show key bindings".to_string())));
+        assert!(help.contains(&("S-Up S-Down".to_string(), "Step by 10 (or 5%)".to_string())));
+    }
+
+    #[test]
+    fn rebinding_moves_keys() {
+        let mut keymap = Keymap::default();
+        keymap.bind(Command::Apply, &[Key::Enter, Key::F(2)]);
+        assert_eq!(keymap.command(Key::Enter), Some(Command::Apply));
+        assert_eq!(keymap.command(Key::Char(' ')), None);
+        assert!(keymap.keys(Command::Refresh).is_empty());
+        assert_eq!(keymap.key_name(Command::Apply), "Enter");
+        assert_eq!(keymap.key_name(Command::Refresh), "-");
+    }
+}
@@ -0,0 +1,233 @@
+// The LED model - LED numbering, states, and the values which can be read
+// and written - shared by the TUI and the other front-ends.
+
+use pca9956b_api::models::{LedInfo, LedState, LedError};
+use serde::Deserialize;
+use serde_json::json;
+
+pub const NUM_LEDS: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueType {
+    Current,
+    Pwm,
+}
+
+impl std::fmt::Display for ValueType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ValueType::Current => write!(f, "Current"),
+            ValueType::Pwm => write!(f, "PWM"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LedState2 {
+    Off,
+    On,
+    Pwm,
+    PwmPlus
+}
+
+impl std::fmt::Display for LedState2 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LedState2::Off => write!(f, "Off"),
+            LedState2::On => write!(f, "On"),
+            LedState2::Pwm => write!(f, "PWM"),
+            LedState2::PwmPlus => write!(f, "PWMPlus"),
+        }
+    }
+}
+
+impl std::str::FromStr for LedState2 {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(LedState2::Off),
+            "on" => Ok(LedState2::On),
+            "pwm" => Ok(LedState2::Pwm),
+            "pwmplus" => Ok(LedState2::PwmPlus),
+            _ => Err(format!("Invalid LED state {}", s)),
+        }
+    }
+}
+
+impl From<LedState2> for LedState {
+    fn from(state: LedState2) -> Self {
+        match state {
+            LedState2::Off => LedState::FALSE,
+            LedState2::On => LedState::TRUE,
+            LedState2::Pwm => LedState::PWM,
+            LedState2::PwmPlus => LedState::PWMPLUS,
+        }
+    }
+}
+
+impl From<LedState> for LedState2 {
+    fn from(state: LedState) -> Self {
+        match state {
+            LedState::FALSE => LedState2::Off,
+            LedState::TRUE => LedState2::On,
+            LedState::PWM => LedState2::Pwm,
+            LedState::PWMPLUS => LedState2::PwmPlus,
+        }
+    }
+}
+
+pub fn error_name(error: LedError) -> &'static str {
+    match error {
+        LedError::NONE => "None",
+        LedError::OPEN => "Open",
+        LedError::SHORT => "Short",
+        LedError::DNE => "DNE",
+    }
+}
+
+pub fn valid_led(led: i32) -> bool {
+    (0..NUM_LEDS as i32).contains(&led)
+}
+
+// Parses a set of LEDs such as 3, 0-7, 1,3,5 or all
+pub fn parse_leds(spec: &str) -> Result<Vec<i32>, String> {
+    if spec == "all" {
+        return Ok((0..NUM_LEDS as i32).collect());
+    }
+    let mut leds = vec![];
+    for part in spec.split(',') {
+        let mut range = part.splitn(2, '-');
+        let parse = |x: Option<&str>| match x.map(|x| x.trim().parse::<i32>()) {
+            Some(Ok(led)) if valid_led(led) => Ok(led),
+            _ => Err(format!("Invalid LEDs {}", spec)),
+        };
+        let first = parse(range.next())?;
+        let last = match range.next() {
+            Some(x) => parse(Some(x))?,
+            None => first,
+        };
+        if last < first {
+            return Err(format!("Invalid LEDs {}", spec));
+        }
+        for led in first..=last {
+            if !leds.contains(&led) {
+                leds.push(led);
+            }
+        }
+    }
+    Ok(leds)
+}
+
+// Parses a named group of LEDs given as name=leds, e.g. desk=0-7
+pub fn parse_group(arg: &str) -> Result<(String, Vec<i32>), String> {
+    let mut parts = arg.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(name), Some(leds)) if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') => {
+            Ok((name.to_string(), parse_leds(leds)?))
+        },
+        _ => Err(format!("Invalid group {}, expected name=leds, e.g. desk=0-7", arg)),
+    }
+}
+
+// Parses a value given in decimal, hex (0x80) or as a percentage of full
+// scale (50%), which must not exceed max
+pub fn parse_value(arg: &str, max: u32) -> Result<u32, String> {
+    let val = if let Some(pct) = arg.strip_suffix('%') {
+        match pct.trim().parse::<f64>() {
+            Ok(pct) if (0.0..=100.0).contains(&pct) => Some((pct * 255.0 / 100.0).round() as u32),
+            _ => None,
+        }
+    } else if let Some(hex) = arg.strip_prefix("0x").or_else(|| arg.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        arg.parse::<u32>().ok()
+    };
+    match val {
+        Some(val) if val <= max => Ok(val),
+        Some(_) => Err(format!("Value {} exceeds maximum {}", arg, max)),
+        None => Err(format!("Invalid value {}", arg)),
+    }
+}
+
+// Formats a set of LEDs the way parse_leds reads them, e.g. 0-3,8
+pub fn format_leds(leds: &[i32]) -> String {
+    let mut leds = leds.to_vec();
+    leds.sort_unstable();
+    leds.dedup();
+    let mut ranges: Vec<(i32, i32)> = vec![];
+    for led in leds {
+        match ranges.last_mut() {
+            Some((_, last)) if *last + 1 == led => *last = led,
+            _ => ranges.push((led, led)),
+        }
+    }
+    ranges.iter()
+        .map(|(first, last)| if first == last { first.to_string() } else { format!("{}-{}", first, last) })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub fn get_value(last_info: &[LedInfo], ty: &ValueType, led: i32) -> Option<u32> {
+    if valid_led(led) {
+        let led = led as usize;
+        if last_info.len() > led {
+            Some(match ty {
+                ValueType::Current => last_info[led].current.unwrap(),
+                ValueType::Pwm => last_info[led].pwm.unwrap(),
+            })
+        } else {
+            None
+        }
+    } else {
+        None // Note global values only writable not readable
+    }
+}
+
+// A change to one LED, as received by the network front-ends
+#[derive(Deserialize)]
+pub struct SetLed {
+    pub state: Option<String>,
+    pub pwm: Option<u32>,
+    pub current: Option<u32>,
+}
+
+impl SetLed {
+    // Checks the whole change before any of it is written, returning the
+    // state to set
+    pub fn validate(&self) -> Result<Option<LedState>, String> {
+        for val in self.pwm.iter().chain(self.current.iter()) {
+            if *val > 255 {
+                return Err(format!("Value {} exceeds maximum 255", val));
+            }
+        }
+        self.state.as_deref().map(|x| x.parse::<LedState2>().map(|x| x.into())).transpose()
+    }
+}
+
+pub fn led_status(led: &LedInfo) -> serde_json::Value {
+    json!({
+        "state": led.state.map(|x| LedState2::from(x).to_string().to_lowercase()),
+        "pwm": led.pwm,
+        "current": led.current,
+        "error": led.error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(state: Option<&str>, pwm: Option<u32>, current: Option<u32>) -> SetLed {
+        SetLed { state: state.map(|x| x.to_string()), pwm, current }
+    }
+
+    #[test]
+    fn validates_led_changes() {
+        assert_eq!(set(Some("pwm"), Some(255), Some(0)).validate(), Ok(Some(LedState::PWM)));
+        assert_eq!(set(None, Some(128), None).validate(), Ok(None));
+        assert_eq!(set(Some("bogus"), Some(128), None).validate(), Err("Invalid LED state bogus".to_string()));
+        assert_eq!(set(Some("on"), Some(1000), None).validate(), Err("Value 1000 exceeds maximum 255".to_string()));
+        assert_eq!(set(None, None, Some(256)).validate(), Err("Value 256 exceeds maximum 255".to_string()));
+    }
+}
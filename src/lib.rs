@@ -0,0 +1,26 @@
+// Library behind pca9956b-cli.  Provides a handle on a PCA9956B (Device), the
+// LED model, the TUI's command processor, and the non-interactive front-ends
+// (scripts, watch, metrics, MQTT, DMX, OSC, HTTP and the control daemon), so
+// that other tools can embed the same behaviour.
+
+pub mod alert;
+pub mod batch;
+pub mod cache;
+pub mod command;
+pub mod daemon;
+pub mod device;
+pub mod dmx;
+pub mod hass;
+pub mod input;
+pub mod keymap;
+pub mod led;
+pub mod metrics;
+pub mod mqtt;
+pub mod osc;
+pub mod rt;
+pub mod script;
+pub mod serve;
+pub mod watch;
+
+pub use device::{Config, Device};
+pub use led::{LedState2, SetLed, ValueType, NUM_LEDS, error_name, format_leds, get_value, led_status, parse_group, parse_leds, parse_value, valid_led};
@@ -1,69 +1,59 @@
-use pca9956b_api::{ApiNoContext, ContextWrapperExt, GetLedInfoAllResponse};
-use pca9956b_api::models::{LedInfo, OpError, LedState, LedError};
-use tokio_core::{reactor, reactor::Core};
-use clap::{App, Arg};
-use swagger::{make_context,make_context_ty};
-use swagger::{ContextBuilder, EmptyContext, XSpanIdString, Push, AuthData};
-use ncurses::{initscr, refresh, getch, endwin, printw, noecho, cbreak, mvprintw, mv, clrtoeol, timeout};
+use pca9956b_api::models::{LedInfo, LedState, LedError};
+use pca9956b_cli::{alert, cache, daemon, dmx, hass, metrics, mqtt, osc, script, serve, watch};
+use pca9956b_cli::{Config, Device, LedState2, ValueType, NUM_LEDS, format_leds, get_value, parse_group, parse_leds, parse_value};
+use pca9956b_cli::input::{process_input, Effect, Entry, EntryKind, Key, Request, State};
+use pca9956b_cli::keymap::{Command as KeyCommand, Keymap, ROW_LEDS};
+use std::collections::VecDeque;
+use std::os::unix::io::RawFd;
+use std::sync::mpsc::Sender;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::unix::AsyncFd;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::MissedTickBehavior;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use ncurses::{initscr, refresh, getch, endwin, addstr, noecho, cbreak, mvaddstr, mv, clrtoeol, clear, timeout, getmaxyx, ERR};
+use ncurses::{keypad, stdscr, set_escdelay, KEY_F, KEY_F0, KEY_ENTER, KEY_BACKSPACE, KEY_UP, KEY_DOWN, KEY_LEFT, KEY_RIGHT};
+use ncurses::{has_colors, start_color, use_default_colors, init_pair, attron, attroff, attr_t, COLOR_PAIR};
+use ncurses::{A_NORMAL, A_BOLD, A_BLINK, A_REVERSE, COLOR_BLACK, COLOR_WHITE, COLOR_GREEN, COLOR_CYAN, COLOR_BLUE};
+use ncurses::{COLOR_YELLOW, COLOR_RED, COLOR_MAGENTA};
+use ncurses::{KEY_SR, KEY_SF, KEY_SLEFT, KEY_SRIGHT, KEY_PPAGE, KEY_NPAGE, KEY_HOME, KEY_END, KEY_IC, KEY_DC, KEY_BTAB, KEY_RESIZE};
 use log::{debug, warn, info};
 use signal_hook::{register, SIGINT, SIGTERM};
 
-struct Config {
-    https: bool,
-    host: String,
-    port: String,
-    bus: i32,
-    addr: i32,
-}
-
-struct Action {
-    exit: bool,
-    refresh_led_info: bool,
-    refresh_selected: bool,
-    refresh_info: bool,
-    info: Option<String>,
-    selected: i32,
-    value_type: Option<ValueType>,
-    new_value: Option<u32>,
-}
-
-#[derive(Debug, Clone, Copy)]
-enum ValueType {
-    Current,
-    Pwm,
-}
-
-impl ValueType {
-    fn from_cmd(ch:i32) -> Option<Self> {
-        match ch {
-            CMD_VALUE_CURRENT => Some(ValueType::Current),
-            CMD_VALUE_PWM => Some(ValueType::Pwm),
-            _ => None,
-        }
-    }
-}
+mod worker;
 
-impl std::fmt::Display for ValueType {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            ValueType::Current => write!(f, "Current"),
-            ValueType::Pwm => write!(f, "PWM"),
-        }
-    }
+enum Command {
+    Tui(TuiConfig),
+    RunScript(String),
+    Watch(u64, alert::Alerts),
+    Metrics(String, u64),
+    Mqtt(mqtt::MqttConfig),
+    Dmx(dmx::DmxConfig),
+    Osc(String),
+    Serve(String, u64, Vec<String>),
+    Daemon(String),
 }
 
-struct State {
-    selected: i32,
-    value_type: Option<ValueType>,
-    new_value: Option<u32>,
+struct TuiConfig {
+    refresh: u64,
+    max_current: u32,
+    groups: Vec<(String, Vec<i32>)>,
+    keymap: Keymap,
 }
 
-type ClientContext = make_context_ty!(ContextBuilder, EmptyContext, Option<AuthData>, XSpanIdString);
-type Client<'a> = swagger::context::ContextWrapper<'a, pca9956b_api::client::Client<hyper::client::ResponseFuture>, ClientContext>;
-
 static QUIT: i32 = 0;
 static ABORT: i32 = 1;
 
+const KEY_LF: i32 = 10;
+const KEY_CR: i32 = 13;
+const KEY_TAB: i32 = 9;
+const KEY_DEL: i32 = 127;
+const KEY_ESC: i32 = 27;
+// How long ncurses waits after an ESC for the rest of an escape sequence
+const ESC_DELAY_MS: i32 = 25;
+
 const START_LINE: i32 = 0;    
 const STATUS_LINE: i32 = 9;    
 const ERRORS_LINE: i32 = 10;    
@@ -72,28 +62,117 @@ const INFO_LINE: i32 = 14;
 const INFO_COLUMN: i32 = 5;    
 const CURSOR_LINE: i32 = 14;    
 const CURSOR_COLUMN: i32 = 78;   
+const SELECTED_WIDTH: usize = 12;
+// Space needed by the controls, and by the bar graphs and activity log
+// drawn beside or below them when there's room
+const MIN_LINES: i32 = 16;
+const MIN_COLS: i32 = 80;
+const BARS_COLUMN: i32 = 5;
+const MIN_BARS_HEIGHT: i32 = 3;
+const MAX_BARS_HEIGHT: i32 = 16;
+const MIN_LOG_HEIGHT: i32 = 3;
+
+// Messages kept in the activity log, beyond which the oldest are dropped
+const MAX_LOG_ENTRIES: usize = 10000;
+// Width of each log line's time and severity
+const LOG_PREFIX_WIDTH: usize = 16;
+
+// Colour pairs for LED states and errors
+const PAIR_OFF: i16 = 1;
+const PAIR_ON: i16 = 2;
+const PAIR_PWM: i16 = 3;
+const PAIR_PWMPLUS: i16 = 4;
+const PAIR_NO_ERROR: i16 = 5;
+const PAIR_OPEN: i16 = 6;
+const PAIR_SHORT: i16 = 7;
+const PAIR_DNE: i16 = 8;
+const PAIR_PWM_BAR: i16 = 9;
+const PAIR_CURRENT_BAR: i16 = 10;
+const PAIR_ERROR: i16 = 11;
 
-const NO_LED: i32 = -1;
-const NUM_LEDS: usize = 24;
-const GLOBAL_LED: i32 = 24;
+// How long an LED's error is flashed for after it changes
+const FLASH_TIME: Duration = Duration::from_secs(5);
+
+// Set once the terminal's colours are set up - otherwise LEDs are shown
+// with plain characters and attributes only
+static COLOUR: AtomicBool = AtomicBool::new(false);
 
 fn main() {
-    initscr();
-    noecho();
-    cbreak();
-    timeout(-1);
     env_logger::init();
-    reg_for_sigs();
 
-    let conf = get_args();
+    let (conf, cmd) = get_args();
     dump_args(&conf);
-    let mut core = reactor::Core::new().unwrap();
-    let client = create_client(&conf, &core);
-    let client = client.with_context(make_context!(ContextBuilder, EmptyContext, None as Option<AuthData>, XSpanIdString(uuid::Uuid::new_v4().to_string())));
+    let mut device = match Device::new(conf) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        },
+    };
 
-    run(&conf, &mut core, &client);
+    match cmd {
+        Command::Tui(tui) => {
+            initscr();
+            noecho();
+            cbreak();
+            keypad(stdscr(), true);
+            init_colours();
+            set_escdelay(ESC_DELAY_MS);
+            timeout(0);
+            reg_for_sigs();
 
-    endwin();
+            run(device, tui);
+
+            endwin();
+        },
+        Command::RunScript(file) => or_exit(script::run_script(&mut device, &file)),
+        Command::Watch(interval, alerts) => {
+            println!("{} Watching PCA9956B bus {} address {} every {}ms", watch::timestamp(), device.conf().bus, device.conf().addr, interval);
+            or_exit(watch::run_watch(&mut device, interval, &alerts));
+        },
+        Command::Metrics(listen, interval) => {
+            println!("Serving metrics on http://{}/metrics", listen);
+            or_exit(metrics::run_metrics(&mut device, &listen, interval));
+        },
+        Command::Mqtt(mqtt_conf) => {
+            println!(
+                "Bridging PCA9956B bus {} address {} to MQTT broker {}:{} under {}/{}/{}",
+                device.conf().bus,
+                device.conf().addr,
+                mqtt_conf.host,
+                mqtt_conf.port,
+                mqtt_conf.prefix,
+                device.conf().bus,
+                device.conf().addr,
+            );
+            or_exit(mqtt::run_mqtt(&mut device, &mqtt_conf));
+        },
+        Command::Dmx(dmx_conf) => {
+            let (first, last) = or_exit(dmx_conf.channels());
+            println!(
+                "Receiving {:?} universe {} on port {}, channels {}-{} to LEDs {:?}",
+                dmx_conf.protocol,
+                dmx_conf.universe,
+                dmx_conf.protocol.port(),
+                first,
+                last,
+                dmx_conf.leds,
+            );
+            or_exit(dmx::run_dmx(&mut device, &dmx_conf));
+        },
+        Command::Osc(listen) => {
+            println!("Receiving OSC on {}", listen);
+            or_exit(osc::run_osc(&mut device, &listen));
+        },
+        Command::Serve(listen, interval, origins) => {
+            println!("Serving HTTP and WebSocket on {}", listen);
+            or_exit(serve::run_serve(&mut device, &listen, interval, origins));
+        },
+        Command::Daemon(socket) => {
+            println!("Serving PCA9956B bus {} address {} on {}", device.conf().bus, device.conf().addr, socket);
+            or_exit(daemon::run_daemon(&mut device, &socket));
+        },
+    }
 }
 
 macro_rules! reg_sig {
@@ -143,7 +222,194 @@ fn on_sigterm() {
     handle_sig!(SIGTERM);
 }
 
-fn get_args() -> Config {
+fn init_colours() {
+    if !has_colors() || start_color() == ERR {
+        return;
+    }
+    // Keep the terminal's own background, where it allows
+    let bg = if use_default_colors() == ERR { COLOR_BLACK } else { -1 };
+    init_pair(PAIR_OFF, COLOR_WHITE, bg);
+    init_pair(PAIR_ON, COLOR_GREEN, bg);
+    init_pair(PAIR_PWM, COLOR_CYAN, bg);
+    init_pair(PAIR_PWMPLUS, COLOR_BLUE, bg);
+    init_pair(PAIR_NO_ERROR, COLOR_GREEN, bg);
+    init_pair(PAIR_OPEN, COLOR_YELLOW, bg);
+    init_pair(PAIR_SHORT, COLOR_RED, bg);
+    init_pair(PAIR_DNE, COLOR_MAGENTA, bg);
+    init_pair(PAIR_PWM_BAR, COLOR_CYAN, bg);
+    init_pair(PAIR_CURRENT_BAR, COLOR_YELLOW, bg);
+    init_pair(PAIR_ERROR, COLOR_RED, bg);
+    COLOUR.store(true, Ordering::Relaxed);
+}
+
+fn colour(pair: i16) -> attr_t {
+    if COLOUR.load(Ordering::Relaxed) {
+        COLOR_PAIR(pair)
+    } else {
+        A_NORMAL()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Panel {
+    line: i32,
+    column: i32,
+    // Rows of bars or log lines below the panel's heading
+    height: i32,
+}
+
+// Where everything goes, for the terminal's current size
+struct Layout {
+    lines: i32,
+    cols: i32,
+    bars: Option<Panel>,
+    log: Option<Panel>,
+}
+
+impl Layout {
+    fn new() -> Self {
+        let (mut lines, mut cols) = (0, 0);
+        getmaxyx(stdscr(), &mut lines, &mut cols);
+        // The bar graphs and log are as wide as the controls.  The bars take
+        // a heading, LED numbers and closing dashes besides their rows, and
+        // the log a heading and closing dashes.
+        let below = lines - MIN_LINES;
+        let log_below = |line| match lines - line - 2 {
+            height if height >= MIN_LOG_HEIGHT => Some(Panel { line, column: 0, height }),
+            _ => None,
+        };
+        let (bars, log) = if cols >= MIN_COLS * 2 && lines >= MIN_BARS_HEIGHT + 3 {
+            let bars = Panel { line: START_LINE, column: MIN_COLS, height: (lines - 3).min(MAX_BARS_HEIGHT) };
+            (Some(bars), log_below(MIN_LINES))
+        } else if below >= MIN_BARS_HEIGHT + 3 + MIN_LOG_HEIGHT + 2 {
+            // Stacked below, the bars and log share the rows between them
+            let height = ((below - 3 - 2) / 2).min(MAX_BARS_HEIGHT);
+            (Some(Panel { line: MIN_LINES, column: 0, height }), log_below(MIN_LINES + height + 3))
+        } else if below >= MIN_LOG_HEIGHT + 2 {
+            // Short of room for both, the log is kept over the bars
+            (None, log_below(MIN_LINES))
+        } else {
+            (None, None)
+        };
+        Layout { lines, cols, bars, log }
+    }
+
+    fn too_small(&self) -> bool {
+        self.lines < MIN_LINES || self.cols < MIN_COLS
+    }
+}
+
+struct Screen {
+    layout: Layout,
+    // The message on the info line
+    info: (Severity, String),
+    // Write results, refreshes and errors
+    log: ActivityLog,
+    // Whether help covers the controls, which then aren't drawn
+    overlay: bool,
+}
+
+impl Screen {
+    fn hidden(&self) -> bool {
+        self.overlay || self.layout.too_small()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Severity {
+    Info,
+    Error,
+}
+
+struct LogEntry {
+    time: chrono::DateTime<chrono::Local>,
+    severity: Severity,
+    msg: String,
+}
+
+// The session's history of actions and errors, with how far it's been
+// scrolled back, in entries from the newest
+struct ActivityLog {
+    entries: VecDeque<LogEntry>,
+    scroll: usize,
+    // Keys to scroll by, for the panel's heading
+    keys: String,
+}
+
+impl ActivityLog {
+    fn new(keymap: &Keymap) -> Self {
+        ActivityLog {
+            entries: VecDeque::new(),
+            scroll: 0,
+            keys: format!("<{}/{}>", keymap.key_name(KeyCommand::LogOlder), keymap.key_name(KeyCommand::LogNewer)),
+        }
+    }
+
+    fn push(&mut self, severity: Severity, msg: &str) {
+        if self.entries.len() == MAX_LOG_ENTRIES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(LogEntry { time: chrono::Local::now(), severity, msg: msg.to_string() });
+        // Anyone looking back through the log keeps their place
+        if self.scroll > 0 {
+            self.scroll = (self.scroll + 1).min(self.entries.len() - 1);
+        }
+    }
+
+    fn scroll(&mut self, entries: i32) {
+        let max = self.entries.len().saturating_sub(1) as i32;
+        self.scroll = (self.scroll as i32 + entries).clamp(0, max) as usize;
+    }
+}
+
+// Tracks when each LED's error last changed, so that new faults stand out
+struct ErrorFlash {
+    errors: Vec<Option<LedError>>,
+    changed: [Option<Instant>; NUM_LEDS],
+}
+
+impl ErrorFlash {
+    fn new() -> Self {
+        ErrorFlash {
+            errors: vec![],
+            changed: [None; NUM_LEDS],
+        }
+    }
+
+    fn update(&mut self, info: &[LedInfo]) {
+        let errors: Vec<Option<LedError>> = info.iter().map(|x| x.error).collect();
+        // Nothing has changed on first sight of the LEDs
+        if self.errors.len() == errors.len() {
+            for (ii, error) in errors.iter().enumerate().take(NUM_LEDS) {
+                if *error != self.errors[ii] {
+                    self.changed[ii] = Some(Instant::now());
+                }
+            }
+        }
+        self.errors = errors;
+    }
+
+    fn flashing(&self, led: usize) -> bool {
+        self.changed[led].map(|x| x.elapsed() < FLASH_TIME).unwrap_or(false)
+    }
+}
+
+// Reports an invalid argument, or a command which failed to start, and exits
+// as clap does
+fn or_exit<T>(result: Result<T, String>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    })
+}
+
+// Parses a numeric option, exiting if it's invalid
+fn parse_arg<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> T {
+    let x = matches.value_of(name).unwrap();
+    or_exit(x.parse::<T>().map_err(|_| format!("Invalid --{} value {}", name, x)))
+}
+
+fn get_args() -> (Config, Command) {
     let matches = App::new("pca9956b-cli")
         .arg(Arg::with_name("https")
             .long("https")
@@ -168,15 +434,266 @@ fn get_args() -> Config {
             .takes_value(true)
             .default_value("32")
             .help("PCA9956B I2C address"))
+        .arg(Arg::with_name("attach")
+            .long("attach")
+            .takes_value(true)
+            .help("Talk to the device via the daemon listening on this socket"))
+        .arg(Arg::with_name("skip-redundant")
+            .long("skip-redundant")
+            .takes_value(true)
+            .value_name("ms")
+            .help("Skip writes which match the LED state read or written within this many milliseconds.  Not with --attach"))
+        .arg(Arg::with_name("refresh")
+            .long("refresh")
+            .takes_value(true)
+            .value_name("ms")
+            .default_value("2000")
+            .help("How often the TUI refreshes LED status, in milliseconds, or 0 for never"))
+        .arg(Arg::with_name("max-current")
+            .long("max-current")
+            .takes_value(true)
+            .value_name("0-255")
+            .default_value("255")
+            .help("Highest current value the TUI will let you set"))
+        .arg(Arg::with_name("group")
+            .long("group")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Named group of LEDs the TUI can select, e.g. desk=0-7"))
+        .arg(Arg::with_name("keymap")
+            .long("keymap")
+            .takes_value(true)
+            .value_name("preset|file")
+            .default_value("qwerty")
+            .help("TUI key bindings: a preset (qwerty, azerty, dvorak) or a JSON file rebinding commands"))
+        .subcommand(SubCommand::with_name("run-script")
+            .about("Runs a file of LED commands (select, state, current, pwm, sleep, refresh, assert, exit)")
+            .arg(Arg::with_name("file")
+                .index(1)
+                .default_value("-")
+                .help("Script file to run, or - to read commands from stdin")))
+        .subcommand(SubCommand::with_name("watch")
+            .about("Polls LED info and prints timestamped state and error changes")
+            .arg(Arg::with_name("interval")
+                .long("interval")
+                .takes_value(true)
+                .default_value("1000")
+                .help("Polling interval in milliseconds"))
+            .arg(Arg::with_name("alert-exec")
+                .long("alert-exec")
+                .takes_value(true)
+                .help("Shell command to run when an LED develops a fault"))
+            .arg(Arg::with_name("alert-file")
+                .long("alert-file")
+                .takes_value(true)
+                .help("File to append to when an LED develops a fault"))
+            .arg(Arg::with_name("alert-webhook")
+                .long("alert-webhook")
+                .takes_value(true)
+                .help("URL to POST JSON to when an LED develops a fault")))
+        .subcommand(SubCommand::with_name("metrics")
+            .about("Serves LED and API health as Prometheus metrics")
+            .arg(Arg::with_name("listen")
+                .long("listen")
+                .takes_value(true)
+                .default_value("0.0.0.0:9956")
+                .help("Address and port to serve /metrics on"))
+            .arg(Arg::with_name("interval")
+                .long("interval")
+                .takes_value(true)
+                .default_value("5000")
+                .help("Polling interval in milliseconds")))
+        .subcommand(SubCommand::with_name("mqtt")
+            .about("Bridges LED control and status to an MQTT broker")
+            .arg(Arg::with_name("broker-host")
+                .long("broker-host")
+                .takes_value(true)
+                .default_value("localhost")
+                .help("MQTT broker hostname"))
+            .arg(Arg::with_name("broker-port")
+                .long("broker-port")
+                .takes_value(true)
+                .default_value("1883")
+                .help("MQTT broker port"))
+            .arg(Arg::with_name("client-id")
+                .long("client-id")
+                .takes_value(true)
+                .default_value("pca9956b-cli")
+                .help("MQTT client ID"))
+            .arg(Arg::with_name("prefix")
+                .long("prefix")
+                .takes_value(true)
+                .default_value("pca9956b")
+                .help("Topic prefix"))
+            .arg(Arg::with_name("interval")
+                .long("interval")
+                .takes_value(true)
+                .default_value("5000")
+                .help("Status polling interval in milliseconds"))
+            .arg(Arg::with_name("homeassistant")
+                .long("homeassistant")
+                .help("Publish Home Assistant discovery config for each LED or group"))
+            .arg(Arg::with_name("discovery-prefix")
+                .long("discovery-prefix")
+                .takes_value(true)
+                .default_value("homeassistant")
+                .help("Home Assistant discovery topic prefix"))
+            .arg(Arg::with_name("group")
+                .long("group")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Group of LEDs to expose as one Home Assistant light, e.g. desk=0-7")))
+        .subcommand(SubCommand::with_name("dmx")
+            .about("Drives LED PWM values from Art-Net or sACN (E1.31) DMX")
+            .arg(Arg::with_name("protocol")
+                .long("protocol")
+                .takes_value(true)
+                .possible_values(&["artnet", "sacn"])
+                .default_value("artnet")
+                .help("DMX over IP protocol to receive"))
+            .arg(Arg::with_name("bind")
+                .long("bind")
+                .takes_value(true)
+                .default_value("0.0.0.0")
+                .help("Address to receive DMX on"))
+            .arg(Arg::with_name("universe")
+                .long("universe")
+                .takes_value(true)
+                .default_value("1")
+                .help("DMX universe to receive"))
+            .arg(Arg::with_name("start-channel")
+                .long("start-channel")
+                .takes_value(true)
+                .default_value("1")
+                .help("DMX channel (1-512) mapped to the first LED"))
+            .arg(Arg::with_name("leds")
+                .long("leds")
+                .takes_value(true)
+                .default_value("all")
+                .help("LEDs to map consecutive channels onto, e.g. 0-7 or 1,3,5"))
+            .arg(Arg::with_name("rate")
+                .long("rate")
+                .takes_value(true)
+                .default_value("20")
+                .help("Maximum updates per second sent to the device")))
+        .subcommand(SubCommand::with_name("osc")
+            .about("Accepts OSC messages such as /led/3/pwm 128 over UDP")
+            .arg(Arg::with_name("listen")
+                .long("listen")
+                .takes_value(true)
+                .default_value("0.0.0.0:9000")
+                .help("Address and port to receive OSC on")))
+        .subcommand(SubCommand::with_name("serve")
+            .about("Serves LED status and control over HTTP and WebSocket, pushing changes")
+            .arg(Arg::with_name("listen")
+                .long("listen")
+                .takes_value(true)
+                .default_value("127.0.0.1:8956")
+                .help("Address and port to serve on"))
+            .arg(Arg::with_name("allow-origin")
+                .long("allow-origin")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Origin of a web page allowed to connect, e.g. http://dashboard.local"))
+            .arg(Arg::with_name("interval")
+                .long("interval")
+                .takes_value(true)
+                .default_value("1000")
+                .help("Polling interval in milliseconds")))
+        .subcommand(SubCommand::with_name("daemon")
+            .about("Owns the device connection and shares it with --attach clients")
+            .arg(Arg::with_name("socket")
+                .long("socket")
+                .takes_value(true)
+                .default_value("/tmp/pca9956b.sock")
+                .help("Unix socket to listen on")))
         .get_matches();
 
-    Config {
+    let conf = Config {
         https: matches.is_present("https"),
         host: matches.value_of("host").unwrap().to_string(),
         port: matches.value_of("port").unwrap().to_string(),
         bus: matches.value_of("bus").unwrap().parse::<i32>().unwrap(),
         addr: matches.value_of("addr").unwrap().parse::<i32>().unwrap(),
+        attach: matches.value_of("attach").map(|x| x.to_string()),
+        write_cache: matches.value_of("skip-redundant")
+            .map(|x| x.parse::<u64>().map_err(|_| format!("Invalid --skip-redundant value {}", x)))
+            .map(|x| cache::WriteCache::new(std::time::Duration::from_millis(or_exit(x)))),
+    };
+
+    // An attached client's cache would miss other clients' writes
+    if conf.attach.is_some() && conf.write_cache.is_some() {
+        or_exit::<()>(Err("--skip-redundant cannot be used with --attach, give it to the daemon instead".to_string()));
     }
+
+    let cmd = match matches.subcommand() {
+        ("run-script", Some(sub)) => Command::RunScript(sub.value_of("file").unwrap().to_string()),
+        ("watch", Some(sub)) => Command::Watch(
+            parse_arg(sub, "interval"),
+            alert::Alerts {
+                exec: sub.value_of("alert-exec").map(|x| x.to_string()),
+                file: sub.value_of("alert-file").map(|x| x.to_string()),
+                webhook: sub.value_of("alert-webhook").map(|x| x.to_string()),
+            },
+        ),
+        ("metrics", Some(sub)) => Command::Metrics(
+            sub.value_of("listen").unwrap().to_string(),
+            parse_arg(sub, "interval"),
+        ),
+        ("mqtt", Some(sub)) => Command::Mqtt(mqtt::MqttConfig {
+            host: sub.value_of("broker-host").unwrap().to_string(),
+            port: parse_arg(sub, "broker-port"),
+            client_id: sub.value_of("client-id").unwrap().to_string(),
+            prefix: sub.value_of("prefix").unwrap().to_string(),
+            interval: parse_arg(sub, "interval"),
+            hass: if sub.is_present("homeassistant") {
+                Some(hass::HassConfig {
+                    discovery_prefix: sub.value_of("discovery-prefix").unwrap().to_string(),
+                    groups: sub.values_of("group")
+                        .map(|x| x.map(|x| or_exit(parse_group(x))).collect())
+                        .unwrap_or_default(),
+                })
+            } else {
+                None
+            },
+        }),
+        ("dmx", Some(sub)) => Command::Dmx(dmx::DmxConfig {
+            protocol: sub.value_of("protocol").unwrap().parse::<dmx::Protocol>().unwrap(),
+            bind: sub.value_of("bind").unwrap().to_string(),
+            universe: parse_arg(sub, "universe"),
+            start_channel: parse_arg(sub, "start-channel"),
+            leds: or_exit(parse_leds(sub.value_of("leds").unwrap())),
+            rate: parse_arg(sub, "rate"),
+        }),
+        ("osc", Some(sub)) => Command::Osc(sub.value_of("listen").unwrap().to_string()),
+        ("serve", Some(sub)) => Command::Serve(
+            sub.value_of("listen").unwrap().to_string(),
+            parse_arg(sub, "interval"),
+            sub.values_of("allow-origin").map(|x| x.map(|x| x.to_string()).collect()).unwrap_or_default(),
+        ),
+        ("daemon", Some(_)) if conf.attach.is_some() => or_exit(Err("The daemon cannot itself attach to a daemon".to_string())),
+        ("daemon", Some(sub)) => Command::Daemon(sub.value_of("socket").unwrap().to_string()),
+        _ => Command::Tui(TuiConfig {
+            refresh: matches.value_of("refresh")
+                .map(|x| x.parse::<u64>().map_err(|_| format!("Invalid --refresh value {}", x)))
+                .map(or_exit)
+                .unwrap(),
+            max_current: or_exit(parse_value(matches.value_of("max-current").unwrap(), 255)
+                .map_err(|e| format!("Invalid --max-current value: {}", e))),
+            groups: matches.values_of("group")
+                .map(|x| x.map(|x| or_exit(parse_group(x))).collect())
+                .unwrap_or_default(),
+            keymap: Keymap::load(matches.value_of("keymap").unwrap()).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }),
+        }),
+    };
+
+    (conf, cmd)
 }
 
 fn dump_args(conf: &Config) {
@@ -185,516 +702,629 @@ fn dump_args(conf: &Config) {
   info!("Arg port:  {}\n", conf.port);
   info!("Arg bus:   {}\n", conf.bus);
   info!("Arg addr:  {}\n", conf.addr);
+  info!("Arg attach: {:?}\n", conf.attach);
+  info!("Arg skip-redundant: {}\n", conf.write_cache.is_some());
 }
 
-fn create_client<'a>(conf: &Config, core: &Core) -> pca9956b_api::client::Client<hyper::client::ResponseFuture> {
-    let base_url = format!("{}://{}:{}",
-                           if conf.https { "https" } else { "http" },
-                           conf.host,
-                           conf.port);
-    if conf.https {
-        pca9956b_api::Client::try_new_https(&base_url)
-            .expect("Failed to create HTTPS client")
-    } else {
-        pca9956b_api::Client::try_new_http(&base_url)
-            .expect("Failed to create HTTP client")
-    }
+// API calls are made by a device worker thread, while the TUI itself runs
+// an event loop on a tokio runtime, waiting on terminal input, the refresh
+// timer and the worker's results together
+fn run(device: Device, tui: TuiConfig) {
+    let (requests, rx) = std::sync::mpsc::channel();
+    let (tx, events) = tokio::sync::mpsc::unbounded_channel();
+    std::thread::spawn(move || worker::serve_requests(device, rx, tx));
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to create runtime");
+    rt.block_on(event_loop(tui, requests, events));
 }
 
-fn run(conf: &Config, core: &mut Core, client: &Client) {
-    output_template();
+async fn event_loop(tui: TuiConfig, requests: Sender<Request>, mut events: UnboundedReceiver<worker::Event>) {
+    let mut screen = Screen {
+        layout: Layout::new(),
+        info: (Severity::Info, String::new()),
+        log: ActivityLog::new(&tui.keymap),
+        overlay: false,
+    };
+    let stdin = match AsyncFd::new(0 as RawFd) {
+        Ok(x) => x,
+        Err(e) => return exit(ABORT, &format!("Failed to watch terminal input: {}", e)),
+    };
+    // ncurses reports a resize as a key press, but only once woken to read one
+    let mut winch = match signal(SignalKind::window_change()) {
+        Ok(x) => x,
+        Err(e) => return exit(ABORT, &format!("Failed to watch for terminal resizes: {}", e)),
+    };
+    let refresh = tui.refresh;
+    let mut ticker = tokio::time::interval(Duration::from_millis(refresh.max(1)));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
     let mut state = State {
-        selected: NO_LED,
-        value_type: None,
-        new_value: None
+        max_current: tui.max_current,
+        groups: tui.groups,
+        keymap: tui.keymap,
+        ..Default::default()
     };
-    let mut last_info: Vec<LedInfo> = vec![];
-    let mut action = process_input(conf, core, client, &state, &last_info, CMD_ENTER); // Reads LED status
+    let mut flash = ErrorFlash::new();
+    // Number of refreshes requested but not yet completed
+    let mut refreshing = 0;
+    let mut announce_refresh = false;
+
+    redraw(&mut screen, &state, &flash);
+    // Read LED status, whatever keys are bound to refreshing
+    let effects = vec![
+        Effect::Request(Request::Refresh),
+        Effect::Info("Refreshing LED status ... please wait".to_string()),
+    ];
+    execute(effects, &state, &flash, &mut screen, &requests, &mut refreshing);
     loop {
-        if action.exit {
-            exit(QUIT, &action.info.clone().unwrap());
-        }
-        if action.refresh_led_info {
-            handle_info(get_info(conf, core, client), &mut last_info);
-            output_status(&last_info);
-        }
-        if action.refresh_selected {
-            state.selected = action.selected;
-            state.value_type = action.value_type;
-            state.new_value = action.new_value;
-            output_selected(&state, &last_info);
+        let mut read_keys = false;
+        tokio::select! {
+            ready = stdin.readable() => {
+                match ready {
+                    Ok(mut guard) => guard.clear_ready(),
+                    Err(e) => return exit(ABORT, &format!("Failed to read terminal input: {}", e)),
+                };
+                read_keys = true;
+            },
+            _ = winch.recv() => read_keys = true,
+            _ = ticker.tick(), if refresh > 0 => {
+                if refreshing == 0 {
+                    requests.send(Request::Refresh).ok();
+                    refreshing += 1;
+                }
+            },
+            Some(event) = events.recv() => match event {
+                worker::Event::Info(info) => {
+                    refreshing -= 1;
+                    handle_info(&mut screen, info, &mut state.info);
+                    flash.update(&state.info);
+                    output_status(&screen, &state, &flash);
+                    output_bars(&screen, &state);
+                    output_selected(&screen, &state);
+                    if announce_refresh && refreshing == 0 {
+                        log_info(&mut screen, "Refreshed LED status");
+                        announce_refresh = false;
+                    }
+                },
+                worker::Event::Written(result) => {
+                    match result {
+                        Ok(x) => log_info(&mut screen, &x),
+                        Err(x) => output_error(&mut screen, &x),
+                    }
+                    // Any refresh already queued may have been read before the write
+                    requests.send(Request::Refresh).ok();
+                    refreshing += 1;
+                },
+            },
         }
-        if action.refresh_info {
-            output_info(&action.info.unwrap());
+        // Keys are read until none are left, as the terminal only signals
+        // input arriving, not input still waiting
+        while read_keys {
+            match read_key() {
+                Some(Key::Resize) => {
+                    screen.layout = Layout::new();
+                    redraw(&mut screen, &state, &flash);
+                },
+                Some(key) => {
+                    let (new_state, effects) = process_input(state, key);
+                    state = new_state;
+                    announce_refresh |= effects.contains(&Effect::Request(Request::Refresh));
+                    execute(effects, &state, &flash, &mut screen, &requests, &mut refreshing);
+                },
+                None => read_keys = false,
+            }
         }
-        action = process_input(conf, core, client, &state, &last_info, getch());
     }
 }
 
-fn get_info(conf: &Config, core: &mut Core, client: &Client) -> GetLedInfoAllResponse {
-    let result = core.run(client.get_led_info_all(conf.bus, conf.addr));
-    match result {
-        Ok(x) => x,
-        _ => {
-            let err = format!("Failure to get PCA9956B info: {:?}\n", result);
-            printw(&err);
-            exit(ABORT, &err);
-            GetLedInfoAllResponse::OperationFailed(OpError{error: Some("API Call Failed".to_string())})
+// Decodes the next key press, if any is waiting.  ncurses' keypad mode turns
+// the terminal's escape sequences into key codes using terminfo, so an ESC
+// reaching here is either a lone Esc or Alt held with another key
+fn read_key() -> Option<Key> {
+    timeout(0);
+    let ch = getch();
+    Some(match ch {
+        ERR => return None,
+        KEY_ESC => match getch() {
+            ERR => Key::Esc,
+            ch2 => match decode_char(ch2) {
+                Key::Char(x) => Key::Alt(x),
+                key => key,
+            },
         },
+        _ => decode_char(ch),
+    })
+}
+
+fn decode_char(ch: i32) -> Key {
+    match ch {
+        KEY_LF | KEY_CR | KEY_ENTER => Key::Enter,
+        KEY_TAB => Key::Tab,
+        KEY_DEL | KEY_BACKSPACE => Key::Backspace,
+        KEY_UP => Key::Up,
+        KEY_DOWN => Key::Down,
+        KEY_LEFT => Key::Left,
+        KEY_RIGHT => Key::Right,
+        KEY_SR => Key::ShiftUp,
+        KEY_SF => Key::ShiftDown,
+        KEY_SLEFT => Key::ShiftLeft,
+        KEY_SRIGHT => Key::ShiftRight,
+        KEY_PPAGE => Key::PageUp,
+        KEY_NPAGE => Key::PageDown,
+        KEY_HOME => Key::Home,
+        KEY_END => Key::End,
+        KEY_IC => Key::Insert,
+        KEY_DC => Key::Delete,
+        KEY_BTAB => Key::BackTab,
+        KEY_RESIZE => Key::Resize,
+        _ if (KEY_F(1)..=KEY_F(12)).contains(&ch) => Key::F((ch - KEY_F0) as u8),
+        _ if (32..127).contains(&ch) => Key::Char(ch as u8 as char),
+        _ => Key::Other(ch),
+    }
+}
+
+fn execute(effects: Vec<Effect>, state: &State, flash: &ErrorFlash, screen: &mut Screen, requests: &Sender<Request>, refreshing: &mut usize) {
+    for effect in effects {
+        match effect {
+            Effect::Request(request) => {
+                if request == Request::Refresh {
+                    *refreshing += 1;
+                }
+                requests.send(request).ok();
+            },
+            Effect::ShowSelected => {
+                // The status strips highlight the selection too
+                output_status(screen, state, flash);
+                output_bars(screen, state);
+                output_selected(screen, state);
+                output_command(screen, state);
+            },
+            Effect::Redraw => redraw(screen, state, flash),
+            Effect::ScrollLog(pages) => {
+                if let Some(log) = screen.layout.log {
+                    screen.log.scroll(pages * (log.height - 1).max(1));
+                    output_log(screen);
+                }
+            },
+            Effect::Info(info) => output_info(screen, &info),
+            Effect::Exit(info) => exit(QUIT, &info),
+        }
     }
 }
 
-fn handle_info(info: GetLedInfoAllResponse, last_info: &mut Vec<LedInfo>) {
+
+fn handle_info(screen: &mut Screen, info: Result<Vec<LedInfo>, String>, last_info: &mut Vec<LedInfo>) {
     match info {
-        GetLedInfoAllResponse::OK(info) => {
+        Ok(mut info) => {
             last_info.clear();
-            last_info.append(&mut info.clone());
+            last_info.append(&mut info);
         },
-        _ => {
-            let err = format!("Failure to get PCA9956B info: {:?}\n", info);
-            printw(&err);
+        // Once the TUI is showing LED status, a failed refresh isn't fatal
+        Err(err) if !last_info.is_empty() => output_error(screen, &err),
+        Err(err) => {
+            addstr(&format!("{}\n", err));
             exit(ABORT, &err);
         },
     }
 }
 
 const LINE_DASHES: &str = "-------------------------------------------------------------------------------\n";
-type CharStatus = [char; NUM_LEDS];
+// Character and attributes shown for each LED
+type CharStatus = [(char, attr_t); NUM_LEDS];
 
-fn print_status_chars(arr: [char; NUM_LEDS]) {
-    arr.into_iter().
+fn print_status_chars(arr: CharStatus) {
+    arr.iter().
         enumerate().
-        filter(|(ii,x)| {
-            printw(&format!("{}", x));
+        filter(|(ii,(ch, attr))| {
+            attron(*attr);
+            addstr(&ch.to_string());
+            attroff(*attr);
             (ii+1) % 4 == 0
         }).
-        for_each(|(_,_)| {printw(" ");});
-}
-
-fn output_template() {
-    mvprintw(START_LINE, 0, LINE_DASHES);
-    printw("                         --- PCA9956B Controller ---\n");
-    printw(LINE_DASHES);
-    printw(" Select LED:  0-7 <q-i>  8-15 <a-k>  16-23 <z-,>  o (global)  p (none)\n");
-    printw(" Select operation:  Off <1>  On <2>  PWM <3>  PWMPlus <4>\n");
-    printw(" Select value:  5 Current  6 PWM  7 Offset  8 GRPFREQ  9 GRPPWM  0 DimBlnk\n");
-    printw(" Modify selected value: <up> <down>   Apply selected value: <space>\n");
-    printw(" Exit: <Esc>  Refresh All: <Enter>\n");
-    printw(LINE_DASHES);
+        for_each(|(_,_)| {addstr(" ");});
+}
+
+// Clears the screen and draws everything afresh, for the current layout
+fn redraw(screen: &mut Screen, state: &State, flash: &ErrorFlash) {
+    clear();
+    screen.overlay = state.help;
+    let layout = &screen.layout;
+    if layout.too_small() {
+        mvaddstr(0, 0, &format!("Terminal too small: need at least {}x{}, have {}x{}",
+                                MIN_COLS, MIN_LINES, layout.cols, layout.lines));
+        refresh();
+        return;
+    }
+    if screen.overlay {
+        output_help(&state.keymap);
+        return;
+    }
+    output_template(screen, &state.keymap);
+    output_status(screen, state, flash);
+    output_bars(screen, state);
+    output_selected(screen, state);
+    output_info_line(screen);
+    output_log(screen);
+}
+
+fn output_template(screen: &Screen, keymap: &Keymap) {
+    use KeyCommand::*;
+    mvaddstr(START_LINE, 0, LINE_DASHES);
+    addstr("                         --- PCA9956B Controller ---\n");
+    addstr(LINE_DASHES);
+    // Help for the current key bindings, clipped if rebound to long key names
+    let k = |cmd| keymap.key_name(cmd);
+    let help = [
+        format!(" Select LED:  0-7 <{}-{}>  8-15 <{}-{}>  16-23 <{}-{}>  <{}> all  <{}> none  <{}> type",
+                k(SelectLed(0)), k(SelectLed(7)), k(SelectLed(8)), k(SelectLed(15)),
+                k(SelectLed(16)), k(SelectLed(23)), k(SelectAll), k(SelectNone), k(TypeLeds)),
+        format!(" Operation: Off <{}> On <{}> PWM <{}> PWMPlus <{}>   Toggle: <{}-{}>  Row: <{}>",
+                k(Mode(LedState2::Off)), k(Mode(LedState2::On)), k(Mode(LedState2::Pwm)), k(Mode(LedState2::PwmPlus)),
+                k(ToggleLed(0)), k(ToggleLed(NUM_LEDS as i32 - 1)), row_keys(keymap)),
+        format!(" Value: Current <{}>  PWM <{}>   Type value: <{}>   Apply value: <{}>",
+                k(Value(ValueType::Current)), k(Value(ValueType::Pwm)), k(TypeValue), k(Apply)),
+        format!(" Modify: <{}/{}> 1  <{}/{}> 10  <{}/{}> 16  <{}/{}> min/max",
+                k(Up(0)), k(Down(0)), k(Up(1)), k(Down(1)), k(Up(2)), k(Down(2)), k(Min), k(Max)),
+        format!(" Percent steps: <{}>  Refresh All: <{}>  Help: <{}>  Command: <{}>  Exit: <{}>",
+                k(PercentSteps), k(Refresh), k(Help), k(Palette), k(Exit)),
+    ];
+    for line in help.iter() {
+        addstr_within(line, MIN_COLS - 1);
+        addstr("\n");
+    }
+    addstr(LINE_DASHES);
     // Status: .op+ .op+ .op+ .op+ .op+ .op+     Key: . Off  p PWM  + PWMPlus o On    
     // Errors: .sox .... .... .... .... ....     Key: . None o Open s Short   x DNE
-    printw(" Status:                                   Key: . Off  p PWM  + PWMPlus o On\n");
-    printw(" Errors:                                   Key: . None o Open s Short   x DNE\n");
-    printw(LINE_DASHES);
+    addstr(" Status:                                   Key: . Off  p PWM  + PWMPlus o On\n");
+    addstr(" Errors:                                   Key: . None o Open s Short   x DNE\n");
+    addstr(LINE_DASHES);
     // Selected: 23  Status: PWMPlus  Value: 255  Applies to: Current  Applied: No  
-    printw("\n");
-    printw(LINE_DASHES);
-    // ... LED 0: Current 254: Value applied    printw(LINE_DASHES);
-    printw(&format!(" ... \n"));
-    printw(LINE_DASHES);
+    addstr("\n");
+    addstr(LINE_DASHES);
+    // ... LED 0: Current 254: Value applied    addstr(LINE_DASHES);
+    addstr(" ... \n");
+    addstr(LINE_DASHES);
+    // Bar graphs of each LED's PWM and current, with the LED numbers below,
+    // and the activity log
+    if let Some(bars) = &screen.layout.bars {
+        mvaddstr(bars.line + bars.height + 2, bars.column, LINE_DASHES.trim_end());
+    }
+    if let Some(log) = &screen.layout.log {
+        mvaddstr(log.line + log.height + 1, log.column, LINE_DASHES.trim_end());
+    }
     mv(CURSOR_LINE, CURSOR_COLUMN); // End of info line
     refresh();
 }
 
-fn output_status(info: &Vec<LedInfo>) {
-    let mut status: CharStatus = ['.'; NUM_LEDS];
-    let mut errors: CharStatus = ['.'; NUM_LEDS];
+// Rows are selected with Alt and any LED's key in the presets, so that's
+// described rather than listing a key for each row
+fn row_keys(keymap: &Keymap) -> String {
+    use KeyCommand::*;
+    let alt_led_keys = (0..NUM_LEDS as i32).all(|led| {
+        keymap.keys(SelectLed(led)).iter().any(|key| match key {
+            Key::Char(ch) => keymap.command(Key::Alt(*ch)) == Some(SelectRow(led / ROW_LEDS)),
+            _ => false,
+        })
+    });
+    if alt_led_keys {
+        "M-LED".to_string()
+    } else {
+        (0..NUM_LEDS as i32 / ROW_LEDS).map(|row| keymap.key_name(SelectRow(row))).collect::<Vec<_>>().join("/")
+    }
+}
+
+// Lists every key binding over the controls, in two columns
+fn output_help(keymap: &Keymap) {
+    let help = keymap.help();
+    let rows = help.len().div_ceil(2);
+    let width = MIN_COLS / 2;
+    mvaddstr(START_LINE, 0, "----------------- --- Key bindings: press any key to return --- ---------------");
+    for (ii, (keys, description)) in help.iter().enumerate() {
+        mv(START_LINE + 1 + (ii % rows) as i32, (ii / rows) as i32 * width);
+        addstr_within(&format!(" {:<15} {}", keys, description), width - 1);
+    }
+    mvaddstr(START_LINE + 1 + rows as i32, 0, LINE_DASHES);
+    refresh();
+}
+
+fn state_char(state: LedState) -> (char, attr_t) {
+    match state {
+        LedState::FALSE => ('.', colour(PAIR_OFF)),
+        LedState::TRUE => ('o', colour(PAIR_ON) | A_BOLD()),
+        LedState::PWM => ('p', colour(PAIR_PWM)),
+        LedState::PWMPLUS => ('+', colour(PAIR_PWMPLUS) | A_BOLD()),
+    }
+}
+
+fn error_char(error: LedError) -> (char, attr_t) {
+    match error {
+        LedError::NONE => ('.', colour(PAIR_NO_ERROR)),
+        LedError::SHORT => ('s', colour(PAIR_SHORT) | A_BOLD()),
+        LedError::OPEN => ('o', colour(PAIR_OPEN) | A_BOLD()),
+        LedError::DNE => ('x', colour(PAIR_DNE)),
+    }
+}
+
+fn print_key(items: &[((char, attr_t), &str)]) {
+    for ((ch, attr), name) in items {
+        addstr(" ");
+        attron(*attr);
+        addstr(&ch.to_string());
+        attroff(*attr);
+        addstr(name);
+    }
+}
+
+fn output_status(screen: &Screen, state: &State, flash: &ErrorFlash) {
+    if screen.hidden() || state.info.is_empty() {
+        return;
+    }
+    let mut status: CharStatus = [('.', A_NORMAL()); NUM_LEDS];
+    let mut errors: CharStatus = [('.', A_NORMAL()); NUM_LEDS];
 
-    info.iter().
+    state.info.iter().
         enumerate().
         for_each(|(ii,x)| {
-            match x.state.unwrap() {
-                LedState::FALSE => status[ii] = '.',
-                LedState::TRUE => status[ii] = 'o',
-                LedState::PWM => status[ii] = 'p',
-                LedState::PWMPLUS => status[ii] = '+',
-            };
-            match x.error.unwrap() {
-                LedError::NONE => errors[ii] = '.',
-                LedError::SHORT => errors[ii] = 's',
-                LedError::OPEN => errors[ii] = 'o',
-                LedError::DNE => errors[ii] = 'x',
-            };
+            status[ii] = state_char(x.state.unwrap());
+            errors[ii] = error_char(x.error.unwrap());
+            if flash.flashing(ii) {
+                errors[ii].1 |= A_BLINK() | A_BOLD();
+            }
         });
+    for led in &state.selected {
+        status[*led as usize].1 |= A_REVERSE();
+        errors[*led as usize].1 |= A_REVERSE();
+    }
 
     // Status: .op+ .op+ .op+ .op+ .op+ .op+     Key: . Off  p PWM  + PWMPlus o On    
     // Errors: .sox .... .... .... .... ....     Key: . None o Open s Short   x DNE
-    mvprintw(STATUS_LINE, 0, " Status: ");
+    mvaddstr(STATUS_LINE, 0, " Status: ");
     print_status_chars(status);
-    printw("    Key: . Off  p PWM  + PWMPlus o On");
-    mvprintw(ERRORS_LINE, 0, " Errors: ");
+    addstr("    Key:");
+    print_key(&[
+        (state_char(LedState::FALSE), " Off "),
+        (state_char(LedState::PWM), " PWM "),
+        (state_char(LedState::PWMPLUS), " PWMPlus"),
+        (state_char(LedState::TRUE), " On"),
+    ]);
+    mvaddstr(ERRORS_LINE, 0, " Errors: ");
     print_status_chars(errors);
-    printw("    Key: . None o Open s Short   x DNE");
+    addstr("    Key:");
+    print_key(&[
+        (error_char(LedError::NONE), " None"),
+        (error_char(LedError::OPEN), " Open"),
+        (error_char(LedError::SHORT), " Short  "),
+        (error_char(LedError::DNE), " DNE"),
+    ]);
     mv(CURSOR_LINE, CURSOR_COLUMN);
     refresh();
 }
 
+// Draws the bar graphs as a pair of columns per LED, PWM then current, with
+// : marking a half-filled row
+fn output_bars(screen: &Screen, state: &State) {
+    let Panel { line, column, height } = match screen.layout.bars {
+        Some(x) if !screen.hidden() && !state.info.is_empty() => x,
+        _ => return,
+    };
+    let pwm_attr = colour(PAIR_PWM_BAR);
+    let current_attr = colour(PAIR_CURRENT_BAR) | A_BOLD();
+    mvaddstr(line, column, " Levels:");
+    print_key(&[(('#', pwm_attr), " PWM "), (('#', current_attr), " Current")]);
+    clrtoeol();
+    let bar = |val: Option<u32>, row: i32| {
+        // Filled half rows
+        let halves = (val.unwrap_or(0) * height as u32 * 2 + 127) / 255;
+        match halves as i32 - row * 2 {
+            x if x >= 2 => '#',
+            1 => ':',
+            _ => ' ',
+        }
+    };
+    for row in 0..height {
+        mv(line + height - row, column);
+        clrtoeol();
+        for (ii, led) in state.info.iter().enumerate().take(NUM_LEDS) {
+            mv(line + height - row, column + BARS_COLUMN + ii as i32 * 3);
+            attron(pwm_attr);
+            addstr(&bar(led.pwm, row).to_string());
+            attroff(pwm_attr);
+            attron(current_attr);
+            addstr(&bar(led.current, row).to_string());
+            attroff(current_attr);
+        }
+    }
+    mv(line + height + 1, column);
+    clrtoeol();
+    for ii in 0..NUM_LEDS as i32 {
+        let attr = if state.selected.contains(&ii) { A_REVERSE() } else { A_NORMAL() };
+        mv(line + height + 1, column + BARS_COLUMN + ii * 3);
+        attron(attr);
+        addstr(&format!("{:<2}", ii));
+        attroff(attr);
+    }
+    mv(CURSOR_LINE, CURSOR_COLUMN);
+    refresh();
+}
+
+// Writes text, cut or padded to the given width - clearing the rest of the
+// controls' line, without touching any panel beside them
+fn addstr_within(text: &str, width: i32) {
+    addstr(&format!("{:<width$.width$}", text, width = width as usize));
+}
+
 fn dashes(num: usize) -> String {
     let mut dashes = String::new();
-    (0..num).into_iter().for_each(|_| dashes.push('-'));
+    (0..num).for_each(|_| dashes.push('-'));
     dashes
 }
 
-fn output_selected(state: &State, last_info: &Vec<LedInfo>) {
-    let led = state.selected;
-    assert!(led >= NO_LED && led <= GLOBAL_LED);
-    let mut selected = format!("{}", led);
+// Describes something about each selected LED - the one thing if they all
+// agree, otherwise that they're mixed
+fn common<T: PartialEq + ToString>(state: &State, width: usize, f: impl Fn(usize) -> Option<T>) -> String {
+    let mut vals = state.selected.iter().map(|led| f(*led as usize));
+    match vals.next() {
+        Some(Some(first)) if vals.all(|x| x.as_ref() == Some(&first)) => first.to_string(),
+        Some(Some(_)) => "mixed".chars().take(width).collect(),
+        _ => dashes(width),
+    }
+}
+
+fn output_selected(screen: &Screen, state: &State) {
+    if screen.hidden() {
+        return;
+    }
+    let last_info = &state.info;
+    let selected = match (&state.entry, state.selected.as_slice()) {
+        (Some(Entry { kind: EntryKind::Leds, text }), _) => format!("{}_", text),
+        (_, []) => dashes(2),
+        (_, leds) if leds.len() == NUM_LEDS => "all".to_string(),
+        (_, leds) => match format_leds(leds) {
+            x if x.len() <= SELECTED_WIDTH => x,
+            _ => format!("{} LEDs", leds.len()),
+        },
+    };
     let val_type = match &state.value_type {
         Some(x) => x.to_string(),
         None => dashes(7),
     };
     let value = match state.value_type {
-        Some(x) => {
-            let val = get_value(last_info, &x, led);
-            match val {
-                Some(x) => format!("{}", x),
-                None => dashes(3),
-            }
-        },
+        Some(x) => common(state, 3, |led| get_value(last_info, &x, led as i32)),
         None => dashes(3),
     };
-    let status;
-    if led == GLOBAL_LED {
-        selected = "**".to_string();
-        status = dashes(7);
-    } else if led == NO_LED {
-        selected = dashes(2);
-        status = dashes(7);
-    } else {
-        let l: LedState2 = last_info[led as usize].state.unwrap().into();
-        status = l.to_string();
-    }
-    let new_val = match state.new_value {
-        Some(x) => format!("{}", x),
-        None => dashes(3),
+    let status = common(state, 7, |led| last_info.get(led).and_then(|x| x.state).map(LedState2::from));
+    let new_val = match (&state.entry, state.new_value) {
+        (Some(Entry { kind: EntryKind::Value, text }), _) => format!("{}_", text),
+        (_, Some(x)) => format!("{}", x),
+        (_, None) => dashes(3),
     };
-    mvprintw(
-        SELECTED_LINE, 
-        0, 
+    mv(SELECTED_LINE, 0);
+    addstr_within(
         &format!(
-            " Selected: {:>2}  Status: {:<7}  Val Type: {:<7}  Cur Val: {:<3}  New Val: {:<3}", 
+            " Selected: {:<width$}  Status: {:<7}  Type: {:<7}  Cur: {:<3}  New: {:<3}", 
             selected, 
             status,
             val_type,
             value,
             new_val,
-        )
+            width = SELECTED_WIDTH,
+        ),
+        MIN_COLS - 1,
     );
     mv(CURSOR_LINE, CURSOR_COLUMN);
     refresh();
 }
 
-fn output_info(info: &str) {
-    mv(INFO_LINE, INFO_COLUMN);
-    clrtoeol();
-    printw(info);
-    mv(CURSOR_LINE, CURSOR_COLUMN);
-    refresh();
-}
-
-const CMD_ENTER: i32 = 10; // LF
-const CMD_APPLY: i32 = 32; // Space
-const CMD_ESC: i32 = 27; // ESC
-const CMD_MODE_OFF: i32 = 49; // 1
-const CMD_MODE_ON: i32 = 50; // 2
-const CMD_MODE_PWM: i32 = 51; // 3
-const CMD_MODE_PWMPLUS: i32 = 52; // 4
-const CMD_MODES: [i32; 4] = [CMD_MODE_OFF, CMD_MODE_ON, CMD_MODE_PWM, CMD_MODE_PWMPLUS];
-const CMD_VALUE_CURRENT: i32 = 53; // 5
-const CMD_VALUE_PWM: i32 = 54; // 6
-const CMD_VALUES_LED: [i32; 2] = [CMD_VALUE_CURRENT, CMD_VALUE_PWM];
-const CMD_VALUE_OFFSET: i32 = 55; // 7
-const CMD_VALUE_GRPFREQ: i32 = 56; // 8
-const CMD_VALUE_GRPPWM: i32 = 57; // 9
-const CMD_VALUE_DIMBLNK: i32 = 48; // 0
-const CMD_VALUES_GLOBAL: [i32; 4] = [CMD_VALUE_OFFSET, CMD_VALUE_GRPFREQ, CMD_VALUE_GRPPWM, CMD_VALUE_DIMBLNK];
-const CMD_UP: i32 = 'A' as i32; // Up arrow is 10, 91, 65.  65 = A
-const CMD_DOWN: i32 = 'B' as i32; // Down arrow is 10, 91, 66.  66 = B
-
-const CMD_LEDS: [i32; 26] = [
-    'p' as i32, // -1 = None
-    'q' as i32, // LED 0
-    'w' as i32, // LED 1
-    'e' as i32, 
-    'r' as i32, 
-    't' as i32, 
-    'y' as i32, 
-    'u' as i32, 
-    'i' as i32, 
-    'a' as i32, 
-    's' as i32, 
-    'd' as i32, 
-    'f' as i32, 
-    'g' as i32, 
-    'h' as i32, 
-    'j' as i32, 
-    'k' as i32, 
-    'z' as i32, 
-    'x' as i32, 
-    'c' as i32, 
-    'v' as i32, 
-    'b' as i32, 
-    'n' as i32, 
-    'm' as i32, 
-    ',' as i32, // LED 23
-    'o' as i32, // Global
-];
-
-fn set_led_state(conf: &Config, core: &mut Core, client: &Client, led: i32, state: LedState) -> String {
-    let s2: LedState2 = state.into();
-    output_info(&format!("Setting LED {} to {}", led, s2));
-    let result = core.run(client.set_led_state(conf.bus, conf.addr, led, state));
-    match result {
-        Ok(_) => format!("Set LED {} to {}", led, s2),
-        _ => {
-            info!("Failed to set LED {} to {}: {:?}\n", led, s2, result);
-            format!("Failed to set LED {} to {}", led, s2)
-        },
+// Shows a command being typed into the palette in place of the info line
+fn output_command(screen: &Screen, state: &State) {
+    if screen.hidden() {
+        return;
+    }
+    if let Some(Entry { kind: EntryKind::Command, text }) = &state.entry {
+        mv(INFO_LINE, INFO_COLUMN);
+        addstr_within(&format!(":{}_", text), MIN_COLS - 1 - INFO_COLUMN);
+        refresh();
     }
-}    
-
-fn valid_led(led: i32) -> bool {
-    led >= 0 && led < NUM_LEDS as i32
 }
 
-fn get_value(last_info: &Vec<LedInfo>, ty: &ValueType, led: i32) -> Option<u32> {
-    if led >= 0 && led < NUM_LEDS as i32 {
-        let led = led as usize;
-        if last_info.len() > led {
-            Some(match ty {
-                ValueType::Current => last_info[led].current.unwrap(),
-                ValueType::Pwm => last_info[led].pwm.unwrap(),
-            })
-        } else {
-            None
-        }
-    } else {
-        None // Note global values only writable not readable
-    }
+fn output_info(screen: &mut Screen, info: &str) {
+    report(screen, Severity::Info, info, false);
 }
 
-enum LedState2 {
-    OFF,
-    ON,
-    PWM,
-    PWMPLUS
+fn log_info(screen: &mut Screen, info: &str) {
+    report(screen, Severity::Info, info, true);
 }
 
-impl std::fmt::Display for LedState2 {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            LedState2::OFF => write!(f, "Off"),
-            LedState2::ON => write!(f, "On"),
-            LedState2::PWM => write!(f, "PWM"),
-            LedState2::PWMPLUS => write!(f, "PWMPlus"),
-        }
-    }
+fn output_error(screen: &mut Screen, err: &str) {
+    report(screen, Severity::Error, err, true);
 }
 
-impl From<i32> for LedState2 {
-    fn from(ch: i32) -> Self {
-        match ch {
-            CMD_MODE_OFF => LedState2::OFF,
-            CMD_MODE_ON => LedState2::ON,
-            CMD_MODE_PWM => LedState2::PWM,
-            CMD_MODE_PWMPLUS => LedState2::PWMPLUS,
-            _ => panic!("Invalid LED state requested")
-        }
+// Shows a message on the info line, and logs it if it's worth keeping -
+// write results, refreshes and errors rather than selection changes and
+// staged values
+fn report(screen: &mut Screen, severity: Severity, msg: &str, logged: bool) {
+    screen.info = (severity, msg.to_string());
+    output_info_line(screen);
+    if logged {
+        screen.log.push(severity, msg);
+        output_log(screen);
     }
 }
 
-impl From<LedState2> for LedState {
-    fn from(state: LedState2) -> Self {
-        match state {
-            LedState2::OFF => LedState::FALSE,
-            LedState2::ON => LedState::TRUE,
-            LedState2::PWM => LedState::PWM,
-            LedState2::PWMPLUS => LedState::PWMPLUS,
-        }
+fn severity_attr(severity: Severity) -> attr_t {
+    match severity {
+        Severity::Info => A_NORMAL(),
+        Severity::Error => colour(PAIR_ERROR) | A_BOLD(),
     }
 }
 
-impl From<LedState> for LedState2 {
-    fn from(state: LedState) -> Self {
-        match state {
-            LedState::FALSE => LedState2::OFF,
-            LedState::TRUE => LedState2::ON,
-            LedState::PWM => LedState2::PWM,
-            LedState::PWMPLUS => LedState2::PWMPLUS,
-        }
+fn output_info_line(screen: &Screen) {
+    let (severity, info) = &screen.info;
+    let severity = *severity;
+    if screen.hidden() {
+        return;
     }
+    mv(INFO_LINE, INFO_COLUMN);
+    attron(severity_attr(severity));
+    addstr_within(info, MIN_COLS - 1 - INFO_COLUMN);
+    attroff(severity_attr(severity));
+    mv(CURSOR_LINE, CURSOR_COLUMN);
+    refresh();
 }
 
-fn set_led_value(conf: &Config, core: &mut Core, client: &Client, led: i32, ty: ValueType, val: u32) -> String {
-    output_info(&format!("Setting LED {} {} to {}", led, ty, val));
-    match ty {
-        ValueType::Current => {
-            let result = core.run(client.set_led_current(conf.bus, conf.addr, led, val as i32));
-            match result {
-                Ok(_) => format!("Set LED {} {} to {}", led, ty, val),
-                _ => {
-                    info!("Failed to set LED {} {} to {}: {:?}\n", led, ty, val, result);
-                    format!("Failed to set LED {} {} to {}", led, ty, val)
-                },
-            }
-        },
-        ValueType::Pwm => {
-            let result = core.run(client.set_led_pwm(conf.bus, conf.addr, led, val as i32));
-            match result {
-                Ok(_) => format!("Set LED {} {} to {}", led, ty, val),
-                _ => {
-                    info!("Failed to set LED {} {} to {}: {:?}\n", led, ty, val, result);
-                    format!("Failed to set LED {} {} to {}", led, ty, val)
-                },
-            }
-        },
-    }
-}    
-
-fn process_input(conf: &Config, core: &mut Core, client: &Client, state: &State, last_info: &Vec<LedInfo>, ch: i32) -> Action {
-    let mut action = Action {
-        exit: false,
-        refresh_led_info: false,
-        refresh_selected: false,
-        refresh_info: false,
-        info: None,
-        selected: state.selected,
-        value_type: state.value_type.clone(),
-        new_value: state.new_value,
+// Shows the log's entries up to the one scrolled back to, newest at the
+// bottom, with long messages wrapped below their time and severity
+fn output_log(screen: &Screen) {
+    let Panel { line, column, height } = match screen.layout.log {
+        Some(x) if !screen.hidden() => x,
+        _ => return,
     };
-    if ch == CMD_ENTER {
-        output_info("Refreshing LED status ... please wait");
-        refresh();
-        action.refresh_led_info = true;
-        action.refresh_selected = true;
-        action.refresh_info = true;
-        action.info = Some("Refreshed LED status".to_string());
-    } else if CMD_MODES.contains(&ch) {
-        let ch: LedState2 = ch.into();
-        let ledstate: LedState = ch.into();
-        let mut leds = vec![];
-        if valid_led(state.selected) {
-            leds.push(state.selected);
-        } else if state.selected == GLOBAL_LED {
-            let mut l = (0..24).collect();
-            leds.append(&mut l);
-        }
-        if !leds.is_empty() {
-            for led in leds {
-                action.info = Some(set_led_state(conf, core, client, led, ledstate));
-            }
-            action.refresh_led_info = true;
-            action.refresh_selected = true;
-            action.refresh_info = true;
+    let log = &screen.log;
+    let width = MIN_COLS as usize - 1;
+    let position = match log.scroll {
+        0 => format!("{} logged", log.entries.len()),
+        x => format!("back to {} of {} logged", log.entries.len() - x, log.entries.len()),
+    };
+    mv(line, column);
+    addstr_within(&format!(" Activity: {}   {} scroll", position, log.keys), width as i32);
+    let mut rows = vec![];
+    for entry in log.entries.iter().rev().skip(log.scroll) {
+        let chars: Vec<char> = entry.msg.chars().collect();
+        let mut wrapped: Vec<String> = chars.chunks(width - LOG_PREFIX_WIDTH).map(|x| x.iter().collect()).collect();
+        if wrapped.is_empty() {
+            wrapped.push(String::new());
         }
-    } else if CMD_LEDS.contains(&ch) {
-        for (ii, led) in CMD_LEDS.iter().enumerate() {
-            if *led == ch {
-                action.refresh_selected = true;
-                action.selected = ii as i32;
-                action.selected -= 1; // 0th index should be -1 - for none
-                action.info = Some(if action.selected == GLOBAL_LED {
-                    "Selected Global".to_string()
-                } else if action.selected == NO_LED {
-                    action.new_value = None;
-                    action.value_type = None;
-                    "No LED selected".to_string()
-                } else {
-                    format!("Selected LED {}", action.selected)
-                });
-                action.refresh_info = true;
-            }
+        for (ii, text) in wrapped.into_iter().enumerate().rev() {
+            let prefix = if ii == 0 {
+                let severity = match entry.severity {
+                    Severity::Info => "info",
+                    Severity::Error => "ERROR",
+                };
+                format!(" {} {:<5}", entry.time.format("%H:%M:%S"), severity)
+            } else {
+                String::new()
+            };
+            rows.push((entry.severity, prefix, text));
         }
-    } else if CMD_VALUES_LED.contains(&ch) {
-        if valid_led(state.selected) || state.selected == GLOBAL_LED {
-            action.value_type = ValueType::from_cmd(ch);
-            action.info = Some(format!("Selected {} Value", action.value_type.clone().unwrap()));
-        } else {
-            action.info = Some("No LED selected".to_string());
+        if rows.len() >= height as usize {
+            break;
         }
-        action.new_value = None;
-        action.refresh_selected = true;
-        action.refresh_info = true;
-    } else if ch == CMD_ESC {
-        timeout(0);
-        let discard = getch();
-        let ch = getch();
-        timeout(-1);
-        match ch {
-            CMD_UP => {
-                action.info = Some(format!("No LED or value selected"));
-                if valid_led(state.selected) || state.selected == GLOBAL_LED {
-                    if state.value_type.is_some() {
-                        action.refresh_selected = true;
-                        action.new_value = match state.new_value {
-                            Some(x) => {
-                                Some(x+1)
-                            },
-                            None => {
-                                let val = get_value(last_info, &state.value_type.unwrap(), state.selected);
-                                if val.is_some() {
-                                    Some(val.unwrap()+1)
-                                } else {
-                                    None
-                                }
-                            },
-                        };
-                        if action.new_value.is_some() && action.new_value.unwrap() > 255 { 
-                            action.new_value = Some(255);
-                        }
-                        action.info = Some(format!("Incremented value"));
-                    }
-                };
-            },   
-            CMD_DOWN => {
-                action.info = Some(format!("No LED or value selected"));
-                if valid_led(state.selected) || state.selected == GLOBAL_LED {
-                    if state.value_type.is_some() {
-                        action.refresh_selected = true;
-                        action.new_value = match state.new_value {
-                            Some(x) => {
-                                Some(x.saturating_sub(1))
-                            },
-                            None => {
-                                let val = get_value(last_info, &state.value_type.unwrap(), state.selected);
-                                if val.is_some() {
-                                    Some(val.unwrap()-1)
-                                } else {
-                                    None
-                                }
-                            },
-                        };
-                        action.info = Some(format!("Decremented value"));
-                    }
-                };
+    }
+    // Oldest rows which don't fit are cut off the top
+    rows.truncate(height as usize);
+    for row in 0..height {
+        mv(line + height - row, column);
+        match rows.get(row as usize) {
+            Some((severity, prefix, text)) => {
+                attron(severity_attr(*severity));
+                addstr(&format!("{:<width$}", prefix, width = LOG_PREFIX_WIDTH));
+                attroff(severity_attr(*severity));
+                addstr_within(text, (width - LOG_PREFIX_WIDTH) as i32);
             },
-            -1 => if discard == -1 { // Means ESC was pressed
-                action.exit = true;
-                action.info = Some("User termination".to_string());
-            }
-            _ => action.info = Some(format!("Unknown key-press {}, {}", discard, ch)),
+            None => addstr_within("", width as i32),
         }
-        action.refresh_info = true;
-    } else if ch == CMD_APPLY {
-        action.info = Some(format!("No LED or value selected, or value unchanged"));
-        // XXX Need to support global led
-        //if valid_led(state.selected) || state.selected == GLOBAL_LED {
-        if valid_led(state.selected) {
-            if state.value_type.is_some() {
-                if state.new_value.is_some() {
-                    action.info = Some(set_led_value(conf, core, client, state.selected, state.value_type.unwrap(), state.new_value.unwrap()));
-                }
-                action.new_value = None;
-                action.refresh_led_info = true;
-                action.refresh_selected = true;
-            }
-        }
-        action.refresh_info = true;
-    } else {
-        action.info = Some(format!("Unknown key-press {}", ch));
-        action.refresh_info = true;
     }
-
-    action
+    mv(CURSOR_LINE, CURSOR_COLUMN);
+    refresh();
 }
-
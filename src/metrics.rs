@@ -0,0 +1,186 @@
+// Prometheus exporter.  LED info is polled in the foreground and the most
+// recent values are served from /metrics by a background listener thread,
+// with each connection handled on its own thread.  LED gauges are only
+// exported while the last poll succeeded - pca9956b_up says whether it did.
+// The API request counters cover these polls, labelled request="led_info".
+
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use pca9956b_api::models::{LedInfo, LedState, LedError};
+use log::{info, warn};
+use crate::Device;
+
+#[derive(Default)]
+struct Metrics {
+    leds: Vec<LedInfo>,
+    requests: u64,
+    failures: u64,
+    duration_sum: f64,
+    last_duration: f64,
+    up: bool,
+    // Unix time of the last successful poll
+    last_success: f64,
+}
+
+// How long a client has to send its request, or take the response
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const LED_STATES: [(LedState, &str); 4] = [
+    (LedState::FALSE, "off"),
+    (LedState::TRUE, "on"),
+    (LedState::PWM, "pwm"),
+    (LedState::PWMPLUS, "pwmplus"),
+];
+
+const LED_ERRORS: [(LedError, &str); 4] = [
+    (LedError::NONE, "none"),
+    (LedError::OPEN, "open"),
+    (LedError::SHORT, "short"),
+    (LedError::DNE, "dne"),
+];
+
+pub fn run_metrics(dev: &mut Device, listen: &str, interval: u64) -> Result<(), String> {
+    let listener = TcpListener::bind(listen).map_err(|e| format!("Failed to listen on {}: {}", listen, e))?;
+
+    let metrics = Arc::new(Mutex::new(Metrics::default()));
+    let server_metrics = metrics.clone();
+    let labels = format!("bus=\"{}\",addr=\"{}\"", dev.conf().bus, dev.conf().addr);
+    std::thread::spawn(move || serve(listener, server_metrics, labels));
+
+    loop {
+        let start = Instant::now();
+        let result = dev.get_info();
+        let duration = start.elapsed().as_secs_f64();
+        {
+            let mut metrics = metrics.lock().unwrap();
+            metrics.requests += 1;
+            metrics.duration_sum += duration;
+            metrics.last_duration = duration;
+            match result {
+                Ok(info) => {
+                    metrics.leds = info;
+                    metrics.up = true;
+                    metrics.last_success = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs_f64()).unwrap_or(0.0);
+                },
+                Err(err) => {
+                    warn!("{}", err);
+                    metrics.failures += 1;
+                    // Stale values would look like the LEDs holding steady
+                    metrics.leds.clear();
+                    metrics.up = false;
+                },
+            }
+        }
+        std::thread::sleep(Duration::from_millis(interval));
+    }
+}
+
+fn serve(listener: TcpListener, metrics: Arc<Mutex<Metrics>>, labels: String) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let metrics = metrics.clone();
+                let labels = labels.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = handle_request(stream, &metrics, &labels) {
+                        info!("Metrics request failed: {}", e);
+                    }
+                });
+            },
+            Err(e) => warn!("Failed to accept metrics connection: {}", e),
+        }
+    }
+}
+
+fn handle_request(mut stream: TcpStream, metrics: &Mutex<Metrics>, labels: &str) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Discard headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut words = request.split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(&metrics.lock().unwrap(), labels)),
+        _ => ("404 Not Found", "Not found\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body,
+    )
+}
+
+fn render(metrics: &Metrics, labels: &str) -> String {
+    let mut out = String::new();
+
+    header(&mut out, "pca9956b_up", "gauge", "1 if the last poll of LED info succeeded");
+    writeln!(out, "pca9956b_up{{{}}} {}", labels, metrics.up as u32).unwrap();
+    header(&mut out, "pca9956b_last_success_timestamp_seconds", "gauge", "Unix time of the last successful poll of LED info");
+    writeln!(out, "pca9956b_last_success_timestamp_seconds{{{}}} {}", labels, metrics.last_success).unwrap();
+
+    header(&mut out, "pca9956b_led_state", "gauge", "1 if the LED is in the given state");
+    for led in &metrics.leds {
+        for (state, name) in LED_STATES.iter() {
+            gauge(&mut out, "pca9956b_led_state", labels, led, Some(("state", name)), (led.state == Some(*state)) as u32);
+        }
+    }
+    header(&mut out, "pca9956b_led_error", "gauge", "1 if the LED reports the given error");
+    for led in &metrics.leds {
+        for (error, name) in LED_ERRORS.iter() {
+            gauge(&mut out, "pca9956b_led_error", labels, led, Some(("error", name)), (led.error == Some(*error)) as u32);
+        }
+    }
+    header(&mut out, "pca9956b_led_current", "gauge", "LED current value, 0-255");
+    for led in &metrics.leds {
+        if let Some(current) = led.current {
+            gauge(&mut out, "pca9956b_led_current", labels, led, None, current);
+        }
+    }
+    header(&mut out, "pca9956b_led_pwm", "gauge", "LED PWM value, 0-255");
+    for led in &metrics.leds {
+        if let Some(pwm) = led.pwm {
+            gauge(&mut out, "pca9956b_led_pwm", labels, led, None, pwm);
+        }
+    }
+
+    // Only LED info is requested, but the label leaves room for other calls
+    let labels = format!("{},request=\"led_info\"", labels);
+    header(&mut out, "pca9956b_api_requests_total", "counter", "Requests made to the API");
+    writeln!(out, "pca9956b_api_requests_total{{{}}} {}", labels, metrics.requests).unwrap();
+    header(&mut out, "pca9956b_api_failures_total", "counter", "Requests to the API which failed");
+    writeln!(out, "pca9956b_api_failures_total{{{}}} {}", labels, metrics.failures).unwrap();
+    header(&mut out, "pca9956b_api_request_duration_seconds", "summary", "API request latency");
+    writeln!(out, "pca9956b_api_request_duration_seconds_sum{{{}}} {}", labels, metrics.duration_sum).unwrap();
+    writeln!(out, "pca9956b_api_request_duration_seconds_count{{{}}} {}", labels, metrics.requests).unwrap();
+    header(&mut out, "pca9956b_api_last_request_duration_seconds", "gauge", "Latency of the most recent API request");
+    writeln!(out, "pca9956b_api_last_request_duration_seconds{{{}}} {}", labels, metrics.last_duration).unwrap();
+
+    out
+}
+
+fn header(out: &mut String, name: &str, ty: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, ty).unwrap();
+}
+
+fn gauge(out: &mut String, name: &str, labels: &str, led: &LedInfo, extra: Option<(&str, &str)>, val: u32) {
+    let index = match led.index {
+        Some(x) => x,
+        None => return,
+    };
+    match extra {
+        Some((label, x)) => writeln!(out, "{}{{{},led=\"{}\",{}=\"{}\"}} {}", name, labels, index, label, x, val).unwrap(),
+        None => writeln!(out, "{}{{{},led=\"{}\"}} {}", name, labels, index, val).unwrap(),
+    }
+}
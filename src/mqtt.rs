@@ -0,0 +1,234 @@
+// MQTT bridge.  LED commands are received on
+// <prefix>/<bus>/<addr>/led/<n>/set and LED status is published, retained,
+// to <prefix>/<bus>/<addr>/led/<n> whenever it changes.
+//
+// A set payload is either a JSON object with any of state, pwm and current,
+// e.g. {"state":"pwm","pwm":128}, or a bare LED state, e.g. pwmplus.
+//
+// Home Assistant lights are also bridged if discovery is enabled - see hass.rs.
+
+use std::sync::mpsc::{channel, Sender, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use pca9956b_api::models::LedInfo;
+use rumqttc::{Client as MqttClient, Connection, Event, LastWill, MqttOptions, Packet, QoS};
+use log::{debug, info, warn};
+use crate::{Config, Device, SetLed, led_status, valid_led};
+use crate::hass::{self, HassConfig, Light};
+
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub prefix: String,
+    pub interval: u64,
+    pub hass: Option<HassConfig>,
+}
+
+enum Incoming {
+    Connected,
+    Publish(String, Vec<u8>),
+}
+
+struct Bridge<'a> {
+    mqtt: MqttClient,
+    base: String,
+    published: Vec<LedInfo>,
+    hass: Option<&'a HassConfig>,
+    lights: Vec<Light>,
+    light_states: Vec<Option<String>>,
+}
+
+pub fn run_mqtt(dev: &mut Device, mqtt_conf: &MqttConfig) -> Result<(), String> {
+    let base = format!("{}/{}/{}", mqtt_conf.prefix, dev.conf().bus, dev.conf().addr);
+    let mut options = MqttOptions::new(mqtt_conf.client_id.clone(), mqtt_conf.host.clone(), mqtt_conf.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(format!("{}/status", base), "offline", QoS::AtLeastOnce, true));
+    let (mqtt, connection) = MqttClient::new(options, 64);
+
+    let (tx, rx) = channel();
+    std::thread::spawn(move || drive_connection(connection, tx));
+
+    let lights = match &mqtt_conf.hass {
+        Some(hass) => hass::lights(hass),
+        None => vec![],
+    };
+    let mut bridge = Bridge {
+        mqtt,
+        base,
+        published: vec![],
+        hass: mqtt_conf.hass.as_ref(),
+        light_states: vec![None; lights.len()],
+        lights,
+    };
+    let interval = Duration::from_millis(mqtt_conf.interval);
+    let mut next_poll = Instant::now();
+    loop {
+        let timeout = next_poll.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(timeout) {
+            Ok(Incoming::Connected) => {
+                bridge.on_connect(dev.conf());
+                next_poll = Instant::now();
+            },
+            Ok(Incoming::Publish(topic, payload)) => {
+                bridge.on_publish(dev, &topic, &payload);
+                next_poll = Instant::now();
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                bridge.publish_status(dev);
+                next_poll = Instant::now() + interval;
+            },
+            Err(RecvTimeoutError::Disconnected) => return Err("MQTT connection thread exited".to_string()),
+        }
+    }
+}
+
+// rumqttc only makes progress while its event loop is iterated, so that is
+// done on its own thread and anything of interest is passed back.
+fn drive_connection(mut connection: Connection, tx: Sender<Incoming>) {
+    for event in connection.iter() {
+        let incoming = match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker");
+                Incoming::Connected
+            },
+            Ok(Event::Incoming(Packet::Publish(p))) => Incoming::Publish(p.topic, p.payload.to_vec()),
+            Ok(x) => {
+                debug!("MQTT event {:?}", x);
+                continue;
+            },
+            Err(e) => {
+                warn!("MQTT connection error: {}", e);
+                std::thread::sleep(Duration::from_secs(1));
+                continue;
+            },
+        };
+        if tx.send(incoming).is_err() {
+            return;
+        }
+    }
+}
+
+impl Bridge<'_> {
+    fn on_connect(&mut self, conf: &Config) {
+        self.subscribe(format!("{}/led/+/set", self.base));
+        if let Some(hass) = self.hass {
+            self.subscribe(format!("{}/light/+/set", self.base));
+            for light in &self.lights {
+                let (topic, payload) = hass::discovery(hass, conf, &self.base, light);
+                self.publish(&topic, payload);
+            }
+        }
+        self.publish(&format!("{}/status", self.base), "online".to_string());
+        // Republish everything in case the broker has lost retained messages
+        self.published.clear();
+        self.light_states.iter_mut().for_each(|x| *x = None);
+    }
+
+    fn subscribe(&self, topic: String) {
+        if let Err(e) = self.mqtt.subscribe(topic.clone(), QoS::AtLeastOnce) {
+            warn!("Failed to subscribe to {}: {}", topic, e);
+        }
+    }
+
+    fn on_publish(&mut self, dev: &mut Device, topic: &str, payload: &[u8]) {
+        if let Some(light) = self.parse_light_topic(topic) {
+            if let Err(err) = hass::apply(dev, light, payload) {
+                warn!("Failed to apply {} to light {}: {}", String::from_utf8_lossy(payload), light.id, err);
+            }
+            self.publish_status(dev);
+            return;
+        }
+        let led = match self.parse_topic(topic) {
+            Some(led) => led,
+            None => {
+                info!("Ignoring message on {}", topic);
+                return;
+            },
+        };
+        let set = match parse_payload(payload) {
+            Ok(set) => set,
+            Err(err) => {
+                warn!("Invalid payload on {}: {}", topic, err);
+                return;
+            },
+        };
+        match dev.apply_set_led(led, &set) {
+            Ok(results) => results.iter().for_each(|x| info!("{}", x)),
+            Err(err) => warn!("{}", err),
+        }
+        self.publish_status(dev);
+    }
+
+    fn parse_topic(&self, topic: &str) -> Option<i32> {
+        let led = topic
+            .strip_prefix(&format!("{}/led/", self.base))?
+            .strip_suffix("/set")?
+            .parse::<i32>()
+            .ok()?;
+        if valid_led(led) {
+            Some(led)
+        } else {
+            None
+        }
+    }
+
+    fn parse_light_topic(&self, topic: &str) -> Option<&Light> {
+        let id = topic
+            .strip_prefix(&format!("{}/light/", self.base))?
+            .strip_suffix("/set")?;
+        self.lights.iter().find(|x| x.id == id)
+    }
+
+    fn publish_status(&mut self, dev: &mut Device) {
+        let info = match dev.get_info() {
+            Ok(info) => info,
+            Err(err) => {
+                warn!("{}", err);
+                return;
+            },
+        };
+        for led in &info {
+            let index = match led.index {
+                Some(x) => x,
+                None => continue,
+            };
+            if self.published.get(index as usize) == Some(led) {
+                continue;
+            }
+            self.publish(&format!("{}/led/{}", self.base, index), led_status(led).to_string());
+        }
+        for (light, published) in self.lights.iter().zip(self.light_states.iter_mut()) {
+            let state = hass::state(light, &info);
+            if published.as_ref() != Some(&state) {
+                let topic = format!("{}/light/{}", self.base, light.id);
+                debug!("Publishing {} to {}", state, topic);
+                if let Err(e) = self.mqtt.publish(topic.clone(), QoS::AtLeastOnce, true, state.clone()) {
+                    warn!("Failed to publish to {}: {}", topic, e);
+                }
+                *published = Some(state);
+            }
+        }
+        self.published = info;
+    }
+
+    fn publish(&self, topic: &str, payload: String) {
+        debug!("Publishing {} to {}", payload, topic);
+        if let Err(e) = self.mqtt.publish(topic, QoS::AtLeastOnce, true, payload) {
+            warn!("Failed to publish to {}: {}", topic, e);
+        }
+    }
+}
+
+fn parse_payload(payload: &[u8]) -> Result<SetLed, String> {
+    let payload = std::str::from_utf8(payload).map_err(|e| e.to_string())?.trim();
+    if payload.starts_with('{') {
+        serde_json::from_str(payload).map_err(|e| e.to_string())
+    } else {
+        Ok(SetLed {
+            state: Some(payload.to_string()),
+            pwm: None,
+            current: None,
+        })
+    }
+}
+
@@ -0,0 +1,247 @@
+// OSC (Open Sound Control) server.  Accepts messages over UDP:
+//
+//   /led/<n>/pwm <int>          /all/pwm <int>
+//   /led/<n>/current <int>      /all/current <int>
+//   /led/<n>/state <string>     /all/state <string>
+//
+// Values may be sent as int32 or float32 (rounded), and states are
+// off|on|pwm|pwmplus.  Bundles are unpacked and their messages applied in
+// order; time tags are ignored.
+
+use std::convert::TryFrom;
+use std::net::UdpSocket;
+use pca9956b_api::models::LedState;
+use log::{debug, info, warn};
+use crate::batch::WriteQueue;
+use crate::{Device, LedState2, ValueType, NUM_LEDS, valid_led};
+
+const BUNDLE_ID: &[u8] = b"#bundle\0";
+
+#[derive(Debug, PartialEq)]
+enum OscArg {
+    Int(i32),
+    Float(f32),
+    Str(String),
+}
+
+#[derive(Debug, PartialEq)]
+struct OscMessage {
+    addr: String,
+    args: Vec<OscArg>,
+}
+
+enum OscCmd {
+    Value(ValueType, u32),
+    State(LedState),
+}
+
+pub fn run_osc(dev: &mut Device, listen: &str) -> Result<(), String> {
+    let socket = UdpSocket::bind(listen).map_err(|e| format!("Failed to listen on {}: {}", listen, e))?;
+
+    let mut buf = [0u8; 4096];
+    loop {
+        let (len, src) = match socket.recv_from(&mut buf) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to receive OSC: {}", e);
+                continue;
+            },
+        };
+        let mut msgs = vec![];
+        if let Err(err) = parse_packet(&buf[..len], &mut msgs) {
+            warn!("Invalid OSC packet from {}: {}", src, err);
+            continue;
+        }
+        for msg in msgs {
+            debug!("Received {:?} from {}", msg, src);
+            if let Err(err) = handle_message(dev, &msg) {
+                warn!("{}: {}", msg.addr, err);
+            }
+        }
+    }
+}
+
+fn handle_message(dev: &mut Device, msg: &OscMessage) -> Result<(), String> {
+    let parts: Vec<&str> = msg.addr.split('/').collect();
+    let (leds, param) = match parts.as_slice() {
+        ["", "led", led, param] => {
+            let led = led.parse::<i32>().ok().filter(|x| valid_led(*x)).ok_or(format!("Invalid LED {}", led))?;
+            (vec![led], *param)
+        },
+        ["", "all", param] => ((0..NUM_LEDS as i32).collect(), *param),
+        _ => return Err("Unknown address".to_string()),
+    };
+    let cmd = match (param, msg.args.as_slice()) {
+        ("pwm", [arg]) => OscCmd::Value(ValueType::Pwm, arg_value(arg)?),
+        ("current", [arg]) => OscCmd::Value(ValueType::Current, arg_value(arg)?),
+        ("state", [OscArg::Str(state)]) => OscCmd::State(state.parse::<LedState2>()?.into()),
+        _ => return Err(format!("Unsupported arguments {:?}", msg.args)),
+    };
+    if let [led] = leds.as_slice() {
+        let result = match cmd {
+            OscCmd::Value(ty, val) => dev.set_led_value(*led, ty, val)?,
+            OscCmd::State(state) => dev.set_led_state(*led, state)?,
+        };
+        info!("{}", result);
+    } else {
+        let mut queue = WriteQueue::new();
+        for led in leds {
+            match cmd {
+                OscCmd::Value(ty, val) => queue.set_value(led, ty, val),
+                OscCmd::State(state) => queue.set_state(led, state),
+            }
+        }
+        queue.flush(dev)?;
+    }
+    Ok(())
+}
+
+fn arg_value(arg: &OscArg) -> Result<u32, String> {
+    let val = match arg {
+        OscArg::Int(x) => *x,
+        OscArg::Float(x) => x.round() as i32,
+        OscArg::Str(x) => return Err(format!("Expected a number, got {}", x)),
+    };
+    if (0..=255).contains(&val) {
+        Ok(val as u32)
+    } else {
+        Err(format!("Value {} out of range 0-255", val))
+    }
+}
+
+fn parse_packet(packet: &[u8], msgs: &mut Vec<OscMessage>) -> Result<(), String> {
+    if packet.starts_with(BUNDLE_ID) {
+        // Skip the bundle ID and time tag, then each element is size prefixed
+        let mut pos = 16;
+        while pos < packet.len() {
+            let size = read_i32(packet, pos)?;
+            let end = usize::try_from(size).ok()
+                .and_then(|x| (pos + 4).checked_add(x))
+                .filter(|x| *x <= packet.len())
+                .ok_or(format!("Invalid bundle element size {}", size))?;
+            parse_packet(&packet[pos + 4..end], msgs)?;
+            pos = end;
+        }
+        Ok(())
+    } else {
+        msgs.push(parse_message(packet)?);
+        Ok(())
+    }
+}
+
+fn parse_message(packet: &[u8]) -> Result<OscMessage, String> {
+    let (addr, mut pos) = read_string(packet, 0)?;
+    if !addr.starts_with('/') {
+        return Err(format!("Invalid address {}", addr));
+    }
+    let mut args = vec![];
+    // Type tags are optional in old implementations, meaning no arguments
+    if pos < packet.len() {
+        let (tags, next) = read_string(packet, pos)?;
+        pos = next;
+        for tag in tags.chars().skip_while(|x| *x == ',') {
+            let arg = match tag {
+                'i' => OscArg::Int(read_i32(packet, pos)?),
+                'f' => OscArg::Float(f32::from_bits(read_i32(packet, pos)? as u32)),
+                's' => {
+                    let (s, next) = read_string(packet, pos)?;
+                    args.push(OscArg::Str(s));
+                    pos = next;
+                    continue;
+                },
+                x => return Err(format!("Unsupported type tag {}", x)),
+            };
+            args.push(arg);
+            pos += 4;
+        }
+    }
+    Ok(OscMessage { addr, args })
+}
+
+// OSC strings are NUL terminated and padded to a multiple of 4 bytes
+fn read_string(packet: &[u8], pos: usize) -> Result<(String, usize), String> {
+    let rest = packet.get(pos..).ok_or("Truncated string")?;
+    let len = rest.iter().position(|x| *x == 0).ok_or("Unterminated string")?;
+    let s = std::str::from_utf8(&rest[..len]).map_err(|e| e.to_string())?;
+    Ok((s.to_string(), pos + (len + 4) / 4 * 4))
+}
+
+fn read_i32(packet: &[u8], pos: usize) -> Result<i32, String> {
+    match packet.get(pos..pos + 4) {
+        Some(x) => Ok(i32::from_be_bytes([x[0], x[1], x[2], x[3]])),
+        None => Err("Truncated argument".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Encodes an OSC string, NUL terminated and padded to 4 bytes
+    fn string(s: &str) -> Vec<u8> {
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.resize((s.len() + 4) / 4 * 4, 0);
+        bytes
+    }
+
+    fn message(addr: &str, tags: &str, args: &[u8]) -> Vec<u8> {
+        [string(addr), string(tags), args.to_vec()].concat()
+    }
+
+    fn bundle(elements: &[Vec<u8>]) -> Vec<u8> {
+        let mut packet = [BUNDLE_ID, &[0, 0, 0, 0, 0, 0, 0, 1]].concat();
+        for element in elements {
+            packet.extend_from_slice(&(element.len() as i32).to_be_bytes());
+            packet.extend_from_slice(element);
+        }
+        packet
+    }
+
+    fn parse(packet: &[u8]) -> Result<Vec<OscMessage>, String> {
+        let mut msgs = vec![];
+        parse_packet(packet, &mut msgs).map(|_| msgs)
+    }
+
+    #[test]
+    fn parses_messages() {
+        let packet = message("/led/3/pwm", ",i", &128i32.to_be_bytes());
+        assert_eq!(parse_message(&packet).unwrap(), OscMessage {
+            addr: "/led/3/pwm".to_string(),
+            args: vec![OscArg::Int(128)],
+        });
+        let packet = message("/all/state", ",sf", &[string("pwm"), 0.5f32.to_be_bytes().to_vec()].concat());
+        assert_eq!(parse_message(&packet).unwrap().args, vec![OscArg::Str("pwm".to_string()), OscArg::Float(0.5)]);
+        // Old implementations may omit the type tags entirely
+        assert_eq!(parse_message(&string("/all/pwm")).unwrap().args, vec![]);
+    }
+
+    #[test]
+    fn parses_bundles() {
+        let first = message("/led/0/pwm", ",i", &1i32.to_be_bytes());
+        let second = message("/led/1/state", ",s", &string("on"));
+        let msgs = parse(&bundle(&[first, bundle(&[second])])).unwrap();
+        let addrs: Vec<&str> = msgs.iter().map(|x| x.addr.as_str()).collect();
+        assert_eq!(addrs, vec!["/led/0/pwm", "/led/1/state"]);
+    }
+
+    #[test]
+    fn rejects_truncated_packets() {
+        let packet = message("/led/3/pwm", ",i", &128i32.to_be_bytes());
+        assert_eq!(parse(&packet[..18]).unwrap_err(), "Truncated argument");
+        assert_eq!(parse(&packet[..6]).unwrap_err(), "Unterminated string");
+        assert_eq!(parse(&string("led")).unwrap_err(), "Invalid address led");
+        let mut packet = bundle(&[packet]);
+        packet.truncate(packet.len() - 1);
+        assert_eq!(parse(&packet).unwrap_err(), "Invalid bundle element size 20");
+    }
+
+    #[test]
+    fn rejects_negative_element_sizes() {
+        let mut packet = bundle(&[]);
+        packet.extend_from_slice(&(-4i32).to_be_bytes());
+        assert_eq!(parse(&packet).unwrap_err(), "Invalid bundle element size -4");
+        let mut packet = bundle(&[]);
+        packet.extend_from_slice(&i32::MAX.to_be_bytes());
+        assert_eq!(parse(&packet).unwrap_err(), format!("Invalid bundle element size {}", i32::MAX));
+    }
+}
@@ -0,0 +1,24 @@
+// Runtime for the API client.  The generated client is built on futures 0.1
+// and hyper 0.12, so its requests have to be driven by a tokio 0.1 runtime,
+// whatever drives the rest of the program.  Core::run blocks on a request.
+
+use futures::Future;
+
+pub struct Core {
+    rt: tokio01::runtime::Runtime,
+}
+
+impl Core {
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Core { rt: tokio01::runtime::Runtime::new()? })
+    }
+
+    pub fn run<F>(&mut self, future: F) -> Result<F::Item, F::Error>
+    where
+        F: Future + Send + 'static,
+        F::Item: Send,
+        F::Error: Send,
+    {
+        self.rt.block_on(future)
+    }
+}
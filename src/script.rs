@@ -0,0 +1,144 @@
+// Line-oriented command scripts, read from a file or stdin.  Each line holds
+// one command in the language described in command.rs, e.g.
+//
+//   select 0-3,8
+//   pwm 50%
+//   state pwm
+//   assert pwm 128
+//
+// Asserts re-read LED info first if anything has been written since.
+// Execution stops at the first failing line, which is reported by number, or
+// at exit.
+
+use std::fs::File;
+use std::io::{stdin, BufRead, BufReader};
+use pca9956b_api::models::LedInfo;
+use log::info;
+use crate::command::{parse_line, Check, Cmd};
+use crate::{Device, LedState2, get_value};
+
+struct Script {
+    selected: Vec<i32>,
+    last_info: Vec<LedInfo>,
+    stale: bool,
+}
+
+pub fn run_script(dev: &mut Device, file: &str) -> Result<(), String> {
+    let (name, reader): (&str, Box<dyn BufRead>) = if file == "-" {
+        ("stdin", Box::new(BufReader::new(stdin())))
+    } else {
+        let f = File::open(file).map_err(|e| format!("Failed to open script {}: {}", file, e))?;
+        (file, Box::new(BufReader::new(f)))
+    };
+
+    let mut script = Script {
+        selected: vec![],
+        last_info: vec![],
+        stale: true,
+    };
+    for (ii, line) in reader.lines().enumerate() {
+        let cmd = line
+            .map_err(|e| format!("Failed to read line: {}", e))
+            .and_then(|line| parse_line(&line, &[]));
+        let result = match cmd {
+            Ok(Some(Cmd::Exit)) => return Ok(()),
+            Ok(Some(cmd)) => script.execute(dev, cmd),
+            Ok(None) => Ok(()),
+            Err(err) => Err(err),
+        };
+        result.map_err(|err| format!("{}:{}: {}", name, ii + 1, err))?;
+    }
+    Ok(())
+}
+
+impl Script {
+    fn execute(&mut self, dev: &mut Device, cmd: Cmd) -> Result<(), String> {
+        match cmd {
+            Cmd::Select(leds) => {
+                self.selected = leds;
+                Ok(())
+            },
+            Cmd::State(leds, state) => {
+                let leds = self.leds(leds)?;
+                self.stale = true;
+                for led in leds {
+                    println!("{}", dev.set_led_state(led, state)?);
+                }
+                Ok(())
+            },
+            Cmd::Value(leds, ty, val) => {
+                let leds = self.leds(leds)?;
+                self.stale = true;
+                for led in leds {
+                    println!("{}", dev.set_led_value(led, ty, val)?);
+                }
+                Ok(())
+            },
+            Cmd::Sleep(duration) => {
+                info!("Sleeping for {:?}", duration);
+                std::thread::sleep(duration);
+                Ok(())
+            },
+            Cmd::Refresh => self.refresh(dev),
+            Cmd::Assert(check) => {
+                self.check_selected()?;
+                if self.stale {
+                    self.refresh(dev)?;
+                }
+                self.selected.iter().try_for_each(|led| self.check(*led, &check))
+            },
+            Cmd::Help => Err("help is only available in the TUI".to_string()),
+            Cmd::Exit => Ok(()),
+        }
+    }
+
+    // The LEDs a write names, or else those selected
+    fn leds(&self, leds: Option<Vec<i32>>) -> Result<Vec<i32>, String> {
+        match leds {
+            Some(leds) => Ok(leds),
+            None => {
+                self.check_selected()?;
+                Ok(self.selected.clone())
+            },
+        }
+    }
+
+    fn check_selected(&self) -> Result<(), String> {
+        if self.selected.is_empty() {
+            Err("No LED selected".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn refresh(&mut self, dev: &mut Device) -> Result<(), String> {
+        self.last_info = dev.get_info()?;
+        self.stale = false;
+        Ok(())
+    }
+
+    fn check(&self, led: i32, check: &Check) -> Result<(), String> {
+        let info = self.last_info.get(led as usize).ok_or(format!("No info for LED {}", led))?;
+        match check {
+            Check::State(state) => match info.state {
+                Some(x) if x == *state => Ok(()),
+                x => Err(format!("Assert failed: LED {} state is {}, expected {}", led, describe(x.map(LedState2::from)), LedState2::from(*state))),
+            },
+            Check::Value(ty, val) => match get_value(&self.last_info, ty, led) {
+                Some(x) if x == *val => Ok(()),
+                x => Err(format!("Assert failed: LED {} {} is {}, expected {}", led, ty, describe(x), val)),
+            },
+            Check::Error(error) => match info.error {
+                Some(x) if x == *error => Ok(()),
+                x => Err(format!("Assert failed: LED {} error is {}, expected {}", led, describe(x), error)),
+            },
+        }
+    }
+}
+
+fn describe<T: std::fmt::Display>(val: Option<T>) -> String {
+    match val {
+        Some(x) => x.to_string(),
+        None => "unknown".to_string(),
+    }
+}
@@ -0,0 +1,457 @@
+// Local HTTP and WebSocket gateway.  The device is polled once, here, and
+// changes are pushed to every connected WebSocket client, so UIs don't each
+// have to poll the API.
+//
+// WebSocket (any path, e.g. /ws):
+//   On connect  {"type":"snapshot","leds":[{"led":0,"state":"off",...},...]}
+//   On change   {"type":"led","led":3,"state":"pwm","pwm":128,...}
+//   Send        {"led":3,"state":"pwm","pwm":128} - led may also be a set
+//               such as "0-7" or "all", and any of state, pwm and current
+//               may be given.  Answered with {"type":"result",...}
+//
+// HTTP:
+//   GET /leds, GET /leds/<n>, POST /leds/<leds> with the same JSON body
+//
+// Requests sent by web pages are refused, so a page open in a browser can't
+// drive the LEDs; --allow-origin admits a page's origin.  Requests must also
+// name the listening address as their Host, so a page can't get around this
+// by pointing its own domain name at the server.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use pca9956b_api::models::LedInfo;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::StatusCode;
+use tungstenite::{Message, WebSocket};
+use log::{debug, info, warn};
+use crate::batch::WriteQueue;
+use crate::{Device, SetLed, ValueType, led_status, parse_leds};
+
+// Largest HTTP request body accepted
+const MAX_BODY: usize = 64 * 1024;
+// Largest HTTP request line and headers accepted
+const MAX_HEADER: usize = 8192;
+// How long a client has to send its request, or take a response
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct WriteRequest {
+    leds: Vec<i32>,
+    set: SetLed,
+    reply: Sender<Value>,
+}
+
+#[derive(Deserialize)]
+struct WriteCommand {
+    led: Value,
+    #[serde(flatten)]
+    set: SetLed,
+}
+
+#[derive(Clone)]
+struct Shared {
+    writes: Sender<WriteRequest>,
+    clients: Arc<Mutex<Vec<Sender<String>>>>,
+    leds: Arc<Mutex<Vec<LedInfo>>>,
+    origins: Arc<Vec<String>>,
+    addr: SocketAddr,
+}
+
+pub fn run_serve(dev: &mut Device, listen: &str, interval: u64, origins: Vec<String>) -> Result<(), String> {
+    let listener = TcpListener::bind(listen).map_err(|e| format!("Failed to listen on {}: {}", listen, e))?;
+
+    let addr = listener.local_addr().map_err(|e| format!("Failed to listen on {}: {}", listen, e))?;
+
+    let (tx, rx) = channel();
+    let shared = Shared {
+        writes: tx,
+        clients: Arc::new(Mutex::new(vec![])),
+        leds: Arc::new(Mutex::new(vec![])),
+        origins: Arc::new(origins),
+        addr,
+    };
+    let accept_shared = shared.clone();
+    std::thread::spawn(move || accept(listener, accept_shared));
+
+    let interval = Duration::from_millis(interval);
+    let mut next_poll = Instant::now();
+    loop {
+        let timeout = next_poll.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(timeout) {
+            Ok(req) => {
+                let result = write(dev, &req);
+                req.reply.send(result).ok();
+                next_poll = Instant::now();
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                poll(dev, &shared);
+                next_poll = Instant::now() + interval;
+            },
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+fn write(dev: &mut Device, req: &WriteRequest) -> Value {
+    if req.leds.len() > 1 {
+        return write_batch(dev, req);
+    }
+    let mut results = vec![];
+    for led in &req.leds {
+        match dev.apply_set_led(*led, &req.set) {
+            Ok(mut x) => results.append(&mut x),
+            Err(err) => return json!({"type": "result", "ok": false, "error": err, "results": results}),
+        }
+    }
+    json!({"type": "result", "ok": true, "results": results})
+}
+
+fn write_batch(dev: &mut Device, req: &WriteRequest) -> Value {
+    let state = match req.set.validate() {
+        Ok(x) => x,
+        Err(err) => return json!({"type": "result", "ok": false, "error": err}),
+    };
+    let mut queue = WriteQueue::new();
+    for led in &req.leds {
+        if let Some(current) = req.set.current {
+            queue.set_value(*led, ValueType::Current, current);
+        }
+        if let Some(pwm) = req.set.pwm {
+            queue.set_value(*led, ValueType::Pwm, pwm);
+        }
+        if let Some(state) = state {
+            queue.set_state(*led, state);
+        }
+    }
+    match queue.flush(dev) {
+        Ok(x) => json!({"type": "result", "ok": true, "results": [x]}),
+        Err(err) => json!({"type": "result", "ok": false, "error": err}),
+    }
+}
+
+fn poll(dev: &mut Device, shared: &Shared) {
+    let info = match dev.get_info() {
+        Ok(info) => info,
+        Err(err) => {
+            warn!("{}", err);
+            return;
+        },
+    };
+    let events: Vec<String> = {
+        let mut leds = shared.leds.lock().unwrap();
+        let events = info.iter()
+            .filter(|x| !leds.contains(x))
+            .map(|x| {
+                let mut event = indexed_status(x);
+                event["type"] = json!("led");
+                event.to_string()
+            })
+            .collect();
+        *leds = info;
+        events
+    };
+    if !events.is_empty() {
+        let mut clients = shared.clients.lock().unwrap();
+        clients.retain(|client| events.iter().all(|x| client.send(x.clone()).is_ok()));
+    }
+}
+
+fn indexed_status(led: &LedInfo) -> Value {
+    let mut status = led_status(led);
+    status["led"] = json!(led.index);
+    status
+}
+
+fn snapshot(shared: &Shared) -> Value {
+    let leds = shared.leds.lock().unwrap();
+    Value::Array(leds.iter().map(indexed_status).collect())
+}
+
+fn accept(listener: TcpListener, shared: Shared) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let shared = shared.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = handle_connection(stream, shared) {
+                        info!("Connection failed: {}", e);
+                    }
+                });
+            },
+            Err(e) => warn!("Failed to accept connection: {}", e),
+        }
+    }
+}
+
+fn handle_connection(stream: TcpStream, shared: Shared) -> Result<(), String> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT)).map_err(|e| e.to_string())?;
+    if is_websocket(&stream).map_err(|e| e.to_string())? {
+        let origins = shared.origins.clone();
+        let addr = shared.addr;
+        // The callback's error type is fixed by tungstenite
+        #[allow(clippy::result_large_err)]
+        let check_origin = move |req: &Request, rsp: Response| -> Result<Response, ErrorResponse> {
+            let header = |name| req.headers().get(name).and_then(|x| x.to_str().ok());
+            let refusal = if !host_allowed(header("host"), addr) {
+                "Host not allowed"
+            } else if !origin_allowed(header("origin"), &origins) {
+                "Origin not allowed"
+            } else {
+                return Ok(rsp);
+            };
+            let mut rsp = ErrorResponse::new(Some(refusal.to_string()));
+            *rsp.status_mut() = StatusCode::FORBIDDEN;
+            Err(rsp)
+        };
+        let ws = tungstenite::accept_hdr(stream, check_origin).map_err(|e| e.to_string())?;
+        handle_websocket(ws, shared)
+    } else {
+        handle_http(stream, shared).map_err(|e| e.to_string())
+    }
+}
+
+// Browsers send the page's origin with WebSocket and cross-site requests.
+// Other clients send none.  Nothing here serves pages, so any page's origin
+// must be given with --allow-origin.
+fn origin_allowed(origin: Option<&str>, allowed: &[String]) -> bool {
+    match origin {
+        Some(origin) => allowed.iter().any(|x| x == origin),
+        None => true,
+    }
+}
+
+// Whether the Host header names the address listened on, as an IP address,
+// or as localhost when listening on loopback.  Any other name could have
+// been pointed at this address by a web page's own DNS.  Only browsers are
+// sure to send a Host, so requests without one are allowed.
+fn host_allowed(host: Option<&str>, addr: SocketAddr) -> bool {
+    let host = match host {
+        Some(x) => x,
+        None => return true,
+    };
+    let (name, port) = match host.strip_prefix('[') {
+        Some(rest) => match rest.split_once(']') {
+            Some((name, "")) => (name, ""),
+            Some((name, port)) => match port.strip_prefix(':') {
+                Some(port) => (name, port),
+                None => return false,
+            },
+            None => return false,
+        },
+        None => match host.rsplit_once(':') {
+            Some((name, port)) => (name, port),
+            None => (host, ""),
+        },
+    };
+    let port = match port {
+        "" => 80,
+        x => match x.parse::<u16>() {
+            Ok(x) => x,
+            Err(_) => return false,
+        },
+    };
+    if port != addr.port() {
+        return false;
+    }
+    if name.eq_ignore_ascii_case("localhost") {
+        return addr.ip().is_loopback() || addr.ip().is_unspecified();
+    }
+    match name.parse::<IpAddr>() {
+        Ok(ip) => ip == addr.ip() || addr.ip().is_unspecified(),
+        Err(_) => false,
+    }
+}
+
+// Looks at, without consuming, the request headers for a WebSocket upgrade
+fn is_websocket(stream: &TcpStream) -> std::io::Result<bool> {
+    let mut buf = [0u8; 8192];
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let len = stream.peek(&mut buf)?;
+        let headers = String::from_utf8_lossy(&buf[..len]).to_lowercase();
+        if headers.contains("\r\n\r\n") || len == buf.len() || Instant::now() > deadline {
+            return Ok(headers.contains("upgrade: websocket"));
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn handle_websocket(mut ws: WebSocket<TcpStream>, shared: Shared) -> Result<(), String> {
+    let (tx, rx) = channel();
+    shared.clients.lock().unwrap().push(tx);
+    let snapshot = json!({"type": "snapshot", "leds": snapshot(&shared)});
+    ws.send(Message::Text(snapshot.to_string())).map_err(|e| e.to_string())?;
+
+    // Reads time out so queued events get sent while the client is quiet
+    ws.get_mut().set_read_timeout(Some(Duration::from_millis(50))).map_err(|e| e.to_string())?;
+    loop {
+        match ws.read() {
+            Ok(Message::Text(text)) => {
+                let reply = handle_command(&shared, text.as_bytes());
+                ws.send(Message::Text(reply.to_string())).map_err(|e| e.to_string())?;
+            },
+            Ok(Message::Close(_)) => return Ok(()),
+            Ok(x) => debug!("Ignoring WebSocket message {:?}", x),
+            Err(tungstenite::Error::Io(ref e)) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => (),
+            Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+            Err(e) => return Err(e.to_string()),
+        }
+        send_events(&mut ws, &rx)?;
+    }
+}
+
+fn send_events(ws: &mut WebSocket<TcpStream>, rx: &Receiver<String>) -> Result<(), String> {
+    for event in rx.try_iter() {
+        ws.send(Message::Text(event)).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn handle_command(shared: &Shared, body: &[u8]) -> Value {
+    let cmd: WriteCommand = match serde_json::from_slice(body) {
+        Ok(x) => x,
+        Err(e) => return json!({"type": "result", "ok": false, "error": e.to_string()}),
+    };
+    let leds = match &cmd.led {
+        Value::Number(x) => parse_leds(&x.to_string()),
+        Value::String(x) => parse_leds(x),
+        x => Err(format!("Invalid LEDs {}", x)),
+    };
+    match leds {
+        Ok(leds) => submit(shared, leds, cmd.set),
+        Err(err) => json!({"type": "result", "ok": false, "error": err}),
+    }
+}
+
+fn submit(shared: &Shared, leds: Vec<i32>, set: SetLed) -> Value {
+    let (reply, result) = channel();
+    if shared.writes.send(WriteRequest { leds, set, reply }).is_err() {
+        return json!({"type": "result", "ok": false, "error": "Device unavailable"});
+    }
+    result.recv().unwrap_or_else(|_| json!({"type": "result", "ok": false, "error": "Device unavailable"}))
+}
+
+fn handle_http(stream: TcpStream, shared: Shared) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    // The request line and headers are read up to MAX_HEADER bytes in all
+    let mut remaining = MAX_HEADER as u64;
+    let mut request = String::new();
+    remaining -= (&mut reader).take(remaining).read_line(&mut request)? as u64;
+    let mut content_length = 0;
+    let mut origin = None;
+    let mut host = None;
+    let mut line = String::new();
+    loop {
+        if remaining == 0 {
+            return respond(stream, "431 Request Header Fields Too Large", json!({"error": format!("Headers exceed {} bytes", MAX_HEADER)}));
+        }
+        let len = (&mut reader).take(remaining).read_line(&mut line)?;
+        remaining -= len as u64;
+        if len <= 2 {
+            break;
+        }
+        let lower = line.to_lowercase();
+        let value = line.split_once(':').map(|x| x.1.trim().to_string());
+        if lower.starts_with("content-length:") {
+            content_length = value.and_then(|x| x.parse::<usize>().ok()).unwrap_or(0);
+        } else if lower.starts_with("origin:") {
+            origin = value;
+        } else if lower.starts_with("host:") {
+            host = value;
+        }
+        line.clear();
+    }
+    if content_length > MAX_BODY {
+        return respond(stream, "413 Payload Too Large", json!({"error": format!("Body exceeds {} bytes", MAX_BODY)}));
+    }
+    if !host_allowed(host.as_deref(), shared.addr) {
+        return respond(stream, "403 Forbidden", json!({"error": "Host not allowed"}));
+    }
+    if !origin_allowed(origin.as_deref(), &shared.origins) {
+        return respond(stream, "403 Forbidden", json!({"error": "Origin not allowed"}));
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body)?;
+
+    let mut words = request.split_whitespace();
+    let method = words.next().unwrap_or("");
+    let path: Vec<&str> = words.next().unwrap_or("").trim_matches('/').split('/').collect();
+    let (status, rsp) = match (method, path.as_slice()) {
+        ("GET", ["leds"]) => ("200 OK", snapshot(&shared)),
+        ("GET", ["leds", led]) => {
+            let leds = snapshot(&shared);
+            match led.parse::<usize>().ok().and_then(|x| leds.get(x).cloned()) {
+                Some(x) => ("200 OK", x),
+                None => ("404 Not Found", json!({"error": "No such LED"})),
+            }
+        },
+        ("POST", ["leds", leds]) => {
+            let cmd = serde_json::from_slice::<SetLed>(&body).map_err(|e| e.to_string());
+            match cmd.and_then(|set| Ok((parse_leds(leds)?, set))) {
+                Ok((leds, set)) => {
+                    let result = submit(&shared, leds, set);
+                    let status = if result["ok"] == json!(true) { "200 OK" } else { "500 Internal Server Error" };
+                    (status, result)
+                },
+                Err(err) => ("400 Bad Request", json!({"error": err})),
+            }
+        },
+        _ => ("404 Not Found", json!({"error": "Not found"})),
+    };
+    respond(stream, status, rsp)
+}
+
+fn respond(mut stream: TcpStream, status: &str, rsp: Value) -> std::io::Result<()> {
+    let rsp = rsp.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        rsp.len(),
+        rsp,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_only_listed_origins() {
+        let allowed = vec!["http://dashboard.local".to_string()];
+        assert!(origin_allowed(None, &allowed));
+        assert!(origin_allowed(Some("http://dashboard.local"), &allowed));
+        assert!(!origin_allowed(Some("http://evil.example:8956"), &allowed));
+        assert!(!origin_allowed(Some("https://dashboard.local"), &allowed));
+        assert!(!origin_allowed(Some("http://127.0.0.1:8956"), &[]));
+    }
+
+    #[test]
+    fn allows_only_the_listening_address_as_host() {
+        let loopback: SocketAddr = "127.0.0.1:8956".parse().unwrap();
+        assert!(host_allowed(None, loopback));
+        assert!(host_allowed(Some("127.0.0.1:8956"), loopback));
+        assert!(host_allowed(Some("localhost:8956"), loopback));
+        assert!(!host_allowed(Some("evil.example:8956"), loopback));
+        assert!(!host_allowed(Some("127.0.0.1:80"), loopback));
+        assert!(!host_allowed(Some("127.0.0.1"), loopback));
+        assert!(!host_allowed(Some("192.168.1.2:8956"), loopback));
+
+        let any: SocketAddr = "0.0.0.0:80".parse().unwrap();
+        assert!(host_allowed(Some("192.168.1.2"), any));
+        assert!(host_allowed(Some("192.168.1.2:80"), any));
+        assert!(!host_allowed(Some("pi.local"), any));
+
+        let v6: SocketAddr = "[::1]:8956".parse().unwrap();
+        assert!(host_allowed(Some("[::1]:8956"), v6));
+        assert!(host_allowed(Some("localhost:8956"), v6));
+        assert!(!host_allowed(Some("[::2]:8956"), v6));
+        assert!(!host_allowed(Some("[::1"), v6));
+        assert!(!host_allowed(Some("[::1]8956"), v6));
+    }
+}
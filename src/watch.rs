@@ -0,0 +1,84 @@
+// Polls the device and prints timestamped LED changes, for soak testing.
+
+use std::time::Duration;
+use pca9956b_api::models::{LedInfo, LedError};
+use log::info;
+use crate::{Device, LedState2, error_name};
+use crate::alert::{Alerts, is_fault};
+
+pub fn run_watch(dev: &mut Device, interval: u64, alerts: &Alerts) -> Result<(), String> {
+    let mut last_info: Vec<LedInfo> = vec![];
+    loop {
+        match dev.get_info() {
+            Ok(info) => {
+                if last_info.is_empty() {
+                    info.iter()
+                        .flat_map(describe_initial)
+                        .for_each(|x| println!("{} {}", timestamp(), x));
+                } else {
+                    last_info.iter()
+                        .zip(info.iter())
+                        .flat_map(|(old, new)| describe_changes(old, new))
+                        .for_each(|x| println!("{} {}", timestamp(), x));
+                    last_info.iter()
+                        .zip(info.iter())
+                        .for_each(|(old, new)| check_alert(dev, alerts, old, new));
+                }
+                last_info = info;
+            },
+            Err(err) => println!("{} {}", timestamp(), err),
+        }
+        info!("Sleeping for {}ms", interval);
+        std::thread::sleep(Duration::from_millis(interval));
+    }
+}
+
+pub fn timestamp() -> String {
+    chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string()
+}
+
+fn check_alert(dev: &mut Device, alerts: &Alerts, old: &LedInfo, new: &LedInfo) {
+    if let (Some(led), Some(prev), Some(error)) = (new.index, old.error, new.error) {
+        if prev != error && is_fault(error) {
+            alerts.raise(dev, led, prev, error, &timestamp());
+        }
+    }
+}
+
+fn describe_initial(info: &LedInfo) -> Option<String> {
+    match (info.index, info.error) {
+        (Some(led), Some(error)) if error != LedError::NONE => {
+            Some(format!("LED {} error is {}", led, error_name(error)))
+        },
+        _ => None,
+    }
+}
+
+fn describe_changes(old: &LedInfo, new: &LedInfo) -> Vec<String> {
+    let mut changes = vec![];
+    let led = match new.index {
+        Some(x) => x,
+        None => return changes,
+    };
+    if let (Some(x), Some(y)) = (old.state, new.state) {
+        if x != y {
+            changes.push(format!("LED {} went from {} to {}", led, LedState2::from(x), LedState2::from(y)));
+        }
+    }
+    if let (Some(x), Some(y)) = (old.error, new.error) {
+        if x != y {
+            changes.push(format!("LED {} error went from {} to {}", led, error_name(x), error_name(y)));
+        }
+    }
+    if let (Some(x), Some(y)) = (old.pwm, new.pwm) {
+        if x != y {
+            changes.push(format!("LED {} PWM went from {} to {}", led, x, y));
+        }
+    }
+    if let (Some(x), Some(y)) = (old.current, new.current) {
+        if x != y {
+            changes.push(format!("LED {} Current went from {} to {}", led, x, y));
+        }
+    }
+    changes
+}
@@ -0,0 +1,46 @@
+// Device worker for the TUI.  Runs on its own thread, making the blocking API
+// calls the TUI asks for and reporting back as each completes, so the TUI's
+// event loop never waits on the network.
+
+use std::sync::mpsc::Receiver;
+use pca9956b_api::models::LedInfo;
+use tokio::sync::mpsc::UnboundedSender;
+use pca9956b_cli::batch::WriteQueue;
+use pca9956b_cli::input::Request;
+use pca9956b_cli::Device;
+
+pub enum Event {
+    Info(Result<Vec<LedInfo>, String>),
+    Written(Result<String, String>),
+}
+
+pub fn serve_requests(mut device: Device, requests: Receiver<Request>, events: UnboundedSender<Event>) {
+    for req in requests {
+        let event = match req {
+            Request::Refresh => Event::Info(device.get_info()),
+            Request::SetState(leds, state) => Event::Written(match leds.as_slice() {
+                [led] => device.set_led_state(*led, state),
+                _ => {
+                    let mut queue = WriteQueue::new();
+                    for led in leds {
+                        queue.set_state(led, state);
+                    }
+                    queue.flush(&mut device)
+                },
+            }),
+            Request::SetValue(leds, ty, val) => Event::Written(match leds.as_slice() {
+                [led] => device.set_led_value(*led, ty, val),
+                _ => {
+                    let mut queue = WriteQueue::new();
+                    for led in leds {
+                        queue.set_value(led, ty, val);
+                    }
+                    queue.flush(&mut device)
+                },
+            }),
+        };
+        if events.send(event).is_err() {
+            return;
+        }
+    }
+}